@@ -2,23 +2,34 @@ extern crate clap;
 extern crate exif;
 extern crate lalrpop_util;
 extern crate regex;
-extern crate walkdir;
 
-use crate::clap::Parser;
+use crate::clap::CommandFactory;
+use crate::clap::FromArgMatches;
+use crate::ocd::config::Config;
 use crate::ocd::Cli;
 use crate::ocd::OcdCommand;
 
 mod ocd;
 
 fn main() {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            println!("Error: {:?}", error);
+            return;
+        }
+    };
     match cli.command {
         OcdCommand::MassRename(args) => {
-            if let Err(error) = crate::ocd::mrn::run(&args) {
+            let args = args.with_config(&config.mrn, matches.subcommand_matches("mrn").unwrap());
+            if let Err(error) = args.and_then(|args| crate::ocd::mrn::run(&args)) {
                 println!("Error: {:?}", error);
             }
         }
         OcdCommand::TimeStampSort(args) => {
+            let args = args.with_config(&config.tss, matches.subcommand_matches("tss").unwrap());
             if let Err(error) = crate::ocd::tss::run(&args) {
                 println!("Error: {:?}", error);
             }
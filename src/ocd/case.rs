@@ -0,0 +1,153 @@
+//! Case-only renames and case-insensitive filesystems.
+//!
+//! On case-insensitive filesystems, such as the vfat and exFAT cards cameras
+//! write to or some SMB shares, `Photo.JPG` and `photo.JPG` are the same entry.
+//! Renaming one into the other directly either does nothing or fails, so it is
+//! done in two steps through a temporary name, and destinations that differ
+//! only in case are considered to collide.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+/// Returns true if the paths differ, but only in case.
+pub(crate) fn is_case_only_rename(src: &Path, dst: &Path) -> bool {
+    match (src.to_str(), dst.to_str()) {
+        (Some(src), Some(dst)) => src != dst && src.to_lowercase() == dst.to_lowercase(),
+        _ => false,
+    }
+}
+
+/// Returns an unused path in the same directory, to rename an entry through.
+pub(crate) fn temporary_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    (0..)
+        .map(|n| path.with_file_name(format!(".{}.ocd-{}-{}", name, process::id(), n)))
+        .find(|candidate| fs::symlink_metadata(candidate).is_err())
+        .unwrap()
+}
+
+/// Determines whether directories are on case-insensitive filesystems,
+/// remembering the answer for each directory.
+pub(crate) struct CaseProbe {
+    insensitive: HashMap<PathBuf, bool>,
+}
+
+impl CaseProbe {
+    pub(crate) fn new() -> Self {
+        CaseProbe {
+            insensitive: HashMap::new(),
+        }
+    }
+
+    /// Looks up an entry of the directory whose name has cased letters under
+    /// the name with its case swapped. The directory is case-insensitive if
+    /// that finds the same entry. Directories that do not exist, or have no
+    /// such entries, are assumed to be case-sensitive.
+    pub(crate) fn is_case_insensitive(&mut self, dir: &Path) -> bool {
+        if let Some(insensitive) = self.insensitive.get(dir) {
+            return *insensitive;
+        }
+        let listed = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let insensitive = fs::read_dir(listed)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .find_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let swapped = swap_case(&name);
+                (swapped != name).then(|| same_file(&entry.path(), &listed.join(swapped)))
+            })
+            .unwrap_or(false);
+        self.insensitive.insert(dir.to_path_buf(), insensitive);
+        insensitive
+    }
+
+    /// Returns the key under which the path is compared for collisions:
+    /// lowercase if its directory is case-insensitive. Bytes that are not
+    /// valid UTF-8 are kept distinct.
+    pub(crate) fn collision_key(&mut self, path: &Path) -> String {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let key = crate::ocd::display_path(path);
+        if self.is_case_insensitive(dir) {
+            key.to_lowercase()
+        } else {
+            key.into_owned()
+        }
+    }
+
+    /// Returns true if the destination found on disk is the source itself:
+    /// the rename only changes its case, in a directory on a case-insensitive
+    /// filesystem.
+    pub(crate) fn is_same_entry(&mut self, src: &Path, dst: &Path) -> bool {
+        is_case_only_rename(src, dst)
+            && self.is_case_insensitive(dst.parent().unwrap_or(Path::new("")))
+            && same_file(src, dst)
+    }
+}
+
+fn swap_case(name: &str) -> String {
+    name.chars()
+        .flat_map(|c| {
+            if c.is_uppercase() {
+                c.to_lowercase().collect::<Vec<char>>()
+            } else {
+                c.to_uppercase().collect::<Vec<char>>()
+            }
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &Path, b: &Path) -> bool {
+    fs::symlink_metadata(b).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn case_only_rename() {
+        assert!(is_case_only_rename(
+            Path::new("dir/Photo.JPG"),
+            Path::new("dir/photo.JPG")
+        ));
+        assert!(!is_case_only_rename(
+            Path::new("dir/Photo.JPG"),
+            Path::new("dir/Photo.JPG")
+        ));
+        assert!(!is_case_only_rename(
+            Path::new("dir/Photo.JPG"),
+            Path::new("dir/photo_1.JPG")
+        ));
+    }
+
+    #[test]
+    fn swap_case_test() {
+        assert_eq!("pHOTO.jpg", swap_case("Photo.JPG"));
+        assert_eq!("0001", swap_case("0001"));
+    }
+
+    #[test]
+    fn temporary_path_in_same_directory() {
+        let path = temporary_path(Path::new("dir/Photo.JPG"));
+        assert_eq!(Some(Path::new("dir")), path.parent());
+        assert_ne!(Path::new("dir/Photo.JPG"), path);
+    }
+}
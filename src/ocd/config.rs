@@ -0,0 +1,172 @@
+//! User configuration.
+//!
+//! The configuration file is read from `$XDG_CONFIG_HOME/ocd/config.toml`, or
+//! `$HOME/.config/ocd/config.toml` if `XDG_CONFIG_HOME` is not set.
+//! It may define named mrn programs, and defaults for the flags of each command:
+//!
+//! ```toml
+//! [mrn]
+//! mode = "all"
+//! undo = true
+//! verbosity = 1
+//! compound_extensions = ["min.js"]
+//!
+//! [mrn.programs]
+//! music = "s,cl,rsu,p '{N} - {X}' '{1}_{2}'"
+//!
+//! [tss]
+//! git = true
+//! bucket = "hour"
+//! ```
+//!
+//! Values given on the command line always take precedence over the
+//! configuration file.
+
+use crate::ocd::extension::ExtensionMode;
+use crate::ocd::order::Order;
+use crate::ocd::portable::Portability;
+use crate::ocd::tss::Bucket;
+use crate::ocd::tss::SortAction;
+use crate::ocd::Confirmation;
+use crate::ocd::Mode;
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// The contents of the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) mrn: MassRenameConfig,
+    pub(crate) tss: TimeStampSortConfig,
+}
+
+/// Named programs and defaults for the Mass Re-Name command.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MassRenameConfig {
+    pub(super) programs: BTreeMap<String, String>,
+    pub(super) verbosity: Option<u8>,
+    pub(super) mode: Option<Mode>,
+    pub(super) git: Option<bool>,
+    pub(super) undo: Option<bool>,
+    pub(super) recurse: Option<bool>,
+    pub(super) extensions: Option<ExtensionMode>,
+    pub(super) compound_extensions: Vec<String>,
+    pub(super) jobs: Option<usize>,
+    pub(super) confirm: Option<Confirmation>,
+    pub(super) portable: Option<Portability>,
+    pub(super) minor_words: Option<Vec<String>>,
+    pub(super) sort_by: Option<Order>,
+}
+
+/// Defaults for the Time Stamp Sort command.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TimeStampSortConfig {
+    pub(super) verbosity: Option<u8>,
+    pub(super) git: Option<bool>,
+    pub(super) undo: Option<bool>,
+    pub(super) recurse: Option<bool>,
+    pub(super) bucket: Option<Bucket>,
+    pub(super) dest: Option<PathBuf>,
+    pub(super) verify: Option<bool>,
+    pub(super) action: Option<SortAction>,
+    pub(super) jobs: Option<usize>,
+    pub(super) confirm: Option<Confirmation>,
+    pub(super) sort_by: Option<Order>,
+}
+
+impl Config {
+    /// Reads the configuration file, or returns the default configuration if
+    /// there is none.
+    pub(crate) fn load() -> Result<Config, Box<dyn Error>> {
+        match path() {
+            None => Ok(Config::default()),
+            Some(path) => match fs::read_to_string(&path) {
+                Ok(contents) => Config::parse(&contents)
+                    .map_err(|err| format!("Error in {}: {}", path.display(), err).into()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+                Err(err) => Err(format!("Error reading {}: {}", path.display(), err).into()),
+            },
+        }
+    }
+
+    pub(super) fn parse(contents: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(contents)
+    }
+}
+
+/// Returns the path to the configuration file.
+fn path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("ocd").join("config.toml"))
+}
+
+/// Returns true if the argument was not given on the command line, in which
+/// case a value from the configuration file may replace it.
+pub(crate) fn is_unset(matches: &ArgMatches, id: &str) -> bool {
+    !matches!(matches.value_source(id), Some(ValueSource::CommandLine))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_empty() {
+        let config = Config::parse("").unwrap();
+        assert!(config.mrn.programs.is_empty());
+        assert_eq!(None, config.mrn.mode);
+        assert_eq!(None, config.tss.git);
+    }
+
+    #[test]
+    fn parse_full() {
+        let contents = r#"
+            [mrn]
+            mode = "all"
+            undo = true
+            verbosity = 2
+            portable = "fat"
+            sort_by = "exif"
+
+            [mrn.programs]
+            music = "s,cl,rsu,p '{N} - {X}' '{1}_{2}'"
+
+            [tss]
+            git = true
+            bucket = "hour"
+            confirm = "each"
+            sort_by = "mtime"
+        "#;
+        let config = Config::parse(contents).unwrap();
+        assert_eq!(
+            Some(&String::from("s,cl,rsu,p '{N} - {X}' '{1}_{2}'")),
+            config.mrn.programs.get("music")
+        );
+        assert_eq!(Some(Mode::All), config.mrn.mode);
+        assert_eq!(Some(true), config.mrn.undo);
+        assert_eq!(Some(2), config.mrn.verbosity);
+        assert_eq!(Some(Portability::Fat), config.mrn.portable);
+        assert_eq!(Some(Order::Exif), config.mrn.sort_by);
+        assert_eq!(Some(true), config.tss.git);
+        assert_eq!(Some(Bucket::Hour), config.tss.bucket);
+        assert_eq!(Some(Confirmation::Each), config.tss.confirm);
+        assert_eq!(Some(Order::Mtime), config.tss.sort_by);
+    }
+
+    #[test]
+    fn parse_unknown_field() {
+        assert!(Config::parse("[mrn]\nmood = \"all\"").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use chrono::Datelike;
 use chrono::NaiveDateTime;
+use chrono::Timelike;
 use exif::In;
 use exif::Tag;
 use exif::Value;
@@ -9,40 +10,74 @@ use std::path::PathBuf;
 use std::sync::LazyLock;
 
 // The default date regex string.
-pub(crate) const DATE_FLORB_REGEX_STR: &str = r"(?<date>[0-9];{4}.?[0-9]{2}.?[0-9]{2}|(?:(?:\d{1,2})\s(?i)(?:jan|january|feb|february|mar|march|apr|april|may|jun|june|jul|july|aug|august|sep|september|oct|october|nov|november|dec|december)\s(?:\d{1,4})))";
+pub(crate) const DATE_FLORB_REGEX_STR: &str = r"(?<date>[0-9]{4}.?[0-9]{2}.?[0-9]{2}|(?:(?:\d{1,2})\s(?i)(?:jan|january|feb|february|mar|march|apr|april|may|jun|june|jul|july|aug|august|sep|september|oct|october|nov|november|dec|december)\s(?:\d{1,4})))";
+// The date and time regex string, a date followed by a time of day,
+// e.g. `2024-05-01 14.22.33`, `20240501_142233` or `20240501_142233123`.
+pub(crate) const DATE_TIME_FLORB_REGEX_STR: &str = r"(?<datetime>(?:[0-9]{4}.?[0-9]{2}.?[0-9]{2}|(?:(?:\d{1,2})\s(?i)(?:jan|january|feb|february|mar|march|apr|april|may|jun|june|jul|july|aug|august|sep|september|oct|october|nov|november|dec|december)\s(?:\d{1,4})))(?:[T_ -]|\s+at\s+)(?:[01]\d|2[0-3])[.:-]?[0-5]\d[.:-]?[0-5]\d(?:[.,]?\d{1,9})?)";
 pub(crate) static DATE_FLORB_REGEX: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(DEFAULT_DATEFINDER_REGEX_STR).unwrap());
 
 /// The default datefinder reges string is the same as the default date regex but includes non-alphanumeric catch-all patterns before and after.
-/// Case A: a date in the format YYYY?MM?DD or YYYYMMDD
+/// Case A: a date in the format YYYY?MM?DD or YYYYMMDD, optionally followed by a time
 /// `(?<a>(?<y1>1\d\d\d|20\d\d).?(?<m1>0[1-9]|1[012]).?(?<d1>0[1-9]|[12]\d|30|31))`
 /// Case B: case insensitive, DD MONTH YYYY
 /// where MONTH may be the full month name or the three letter short version.
 /// `(?i)(?<b>(?<d2>\d{1,2})\s(?<m2>jan|january|feb|february|mar|march|apr|april|may|jun|june|jul|july|aug|august|sep|september|oct|october|nov|november|dec|december)\s(?<y2>\d{1,4}))`
-const DEFAULT_DATEFINDER_REGEX_STR: &str = r"\D*(?<a>(?<y1>1\d\d\d|20\d\d).?(?<m1>0[1-9]|1[012]).?(?<d1>0[1-9]|[12]\d|30|31))|(?i)(?<b>(?<d2>\d{1,2})\s(?<m2>jan|january|feb|february|mar|march|apr|april|may|jun|june|jul|july|aug|august|sep|september|oct|october|nov|november|dec|december)\s(?<y2>\d{1,4}))\D*";
+/// Time: HH?MM?SS with an optional sub-second fraction, separated from the date
+/// by `T`, `_`, `-`, a space, or the word `at`, as in phone and screen capture filenames,
+/// and not followed by another digit:
+/// `(?:(?:[T_ -]|\s+at\s+)(?<hh>[01]\d|2[0-3])[.:-]?(?<mm>[0-5]\d)[.:-]?(?<ss>[0-5]\d)(?:[.,]?(?<fs>\d{1,9}))?(?:\D|$))?`
+const DEFAULT_DATEFINDER_REGEX_STR: &str = r"\D*(?<a>(?<y1>1\d\d\d|20\d\d).?(?<m1>0[1-9]|1[012]).?(?<d1>0[1-9]|[12]\d|30|31))(?:(?:[T_ -]|\s+at\s+)(?<hh1>[01]\d|2[0-3])[.:-]?(?<mm1>[0-5]\d)[.:-]?(?<ss1>[0-5]\d)(?:[.,]?(?<fs1>\d{1,9}))?(?:\D|$))?|(?i)(?<b>(?<d2>\d{1,2})\s(?<m2>jan|january|feb|february|mar|march|apr|april|may|jun|june|jul|july|aug|august|sep|september|oct|october|nov|november|dec|december)\s(?<y2>\d{1,4}))(?:(?:[T_ -]|\s+at\s+)(?<hh2>[01]\d|2[0-3])[.:-]?(?<mm2>[0-5]\d)[.:-]?(?<ss2>[0-5]\d)(?:[.,]?(?<fs2>\d{1,9}))?(?:\D|$))?\D*";
 pub(crate) static DEFAULT_DATEFINDER_REGEX: LazyLock<Regex> =
     LazyLock::new(|| Regex::new(DEFAULT_DATEFINDER_REGEX_STR).unwrap());
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub(crate) enum DateSource {
     Filename,
     Exif,
     Filesystem,
 }
 
-pub(crate) fn regex_date(haystack: &str) -> Option<(u32, u32, u32)> {
+/// A time of day found next to a date.
+/// The sub-second fraction, if present, is stored in nanoseconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct Time {
+    pub(crate) hour: u32,
+    pub(crate) minute: u32,
+    pub(crate) second: u32,
+    pub(crate) nanosecond: Option<u32>,
+}
+
+impl Time {
+    pub(crate) fn new(hour: u32, minute: u32, second: u32) -> Self {
+        Time {
+            hour,
+            minute,
+            second,
+            nanosecond: None,
+        }
+    }
+
+    pub(crate) fn with_nanosecond(mut self, nanosecond: Option<u32>) -> Self {
+        self.nanosecond = nanosecond;
+        self
+    }
+}
+
+pub(crate) fn regex_date(haystack: &str) -> Option<(u32, u32, u32, Option<Time>)> {
     DEFAULT_DATEFINDER_REGEX.captures(haystack).map(|capture| {
+        let number = |name: &str| capture.name(name).unwrap().as_str().parse::<u32>().unwrap();
         if capture.name("a").is_some() {
-            let year = capture.get(2).unwrap().as_str().parse::<u32>().unwrap();
-            let month = capture.get(3).unwrap().as_str().parse::<u32>().unwrap();
-            let day = capture.get(4).unwrap().as_str().parse::<u32>().unwrap();
-            (year, month, day)
+            let year = number("y1");
+            let month = number("m1");
+            let day = number("d1");
+            (year, month, day, regex_time(&capture, "1"))
         } else if capture.name("b").is_some() {
-            let year = capture.get(8).unwrap().as_str().parse::<u32>().unwrap();
-            let month = capture.get(7).unwrap().as_str();
+            let year = number("y2");
+            let month = capture.name("m2").unwrap().as_str();
             let month = english_month_to_number(month);
-            let day = capture.get(6).unwrap().as_str().parse::<u32>().unwrap();
-            (year, month, day)
+            let day = number("d2");
+            (year, month, day, regex_time(&capture, "2"))
         } else {
             // This branch is unreachable because if there are no captures,
             // `map` will pass the `None` value directly. If the value was `Some`
@@ -53,6 +88,29 @@ pub(crate) fn regex_date(haystack: &str) -> Option<(u32, u32, u32)> {
     })
 }
 
+/// Extracts the optional time groups for either case of the datefinder regex.
+fn regex_time(capture: &regex::Captures, case: &str) -> Option<Time> {
+    let number = |name: &str| {
+        capture
+            .name(&format!("{name}{case}"))
+            .map(|m| m.as_str().parse::<u32>().unwrap())
+    };
+    let hour = number("hh")?;
+    let minute = number("mm")?;
+    let second = number("ss")?;
+    let nanosecond = capture
+        .name(&format!("fs{case}"))
+        .map(|m| fraction_to_nanoseconds(m.as_str()));
+    Some(Time::new(hour, minute, second).with_nanosecond(nanosecond))
+}
+
+/// Converts the digits of a decimal fraction of a second into nanoseconds,
+/// e.g. `"123"` into `123_000_000`.
+fn fraction_to_nanoseconds(digits: &str) -> u32 {
+    let digits = &digits[..digits.len().min(9)];
+    format!("{digits:0<9}").parse::<u32>().unwrap()
+}
+
 fn english_month_to_number(month: &str) -> u32 {
     match month.to_lowercase().as_str() {
         "jan" | "january" => 1,
@@ -73,12 +131,12 @@ fn english_month_to_number(month: &str) -> u32 {
     }
 }
 
-/// Given a filename, extracts a date by matching against a regex.
-pub(crate) fn filename_date(file_name: &Path) -> Option<(DateSource, u32, u32, u32)> {
-    file_name
-        .to_str()
-        .and_then(crate::ocd::date::regex_date)
-        .map(|(year, month, day)| (DateSource::Filename, year, month, day))
+/// Given a filename, extracts a date and, if present, a time by matching against a regex.
+/// Bytes that are not valid UTF-8 are replaced, so that the date is still found
+/// in names from legacy encodings.
+pub(crate) fn filename_date(file_name: &Path) -> Option<(DateSource, u32, u32, u32, Option<Time>)> {
+    crate::ocd::date::regex_date(&file_name.to_string_lossy())
+        .map(|(year, month, day, time)| (DateSource::Filename, year, month, day, time))
 }
 
 /// Attempts to extract the creation data from the EXIF data in an image file.
@@ -91,7 +149,8 @@ pub(crate) fn filename_date(file_name: &Path) -> Option<(DateSource, u32, u32, u
 /// - if for some reason the exif tag is not in the right format and
 ///   `chrono::NaiveDateTime::parse_from_str` cannot parse it, parse the result
 ///   with `dateparser::parse` as a date with format `"%Y-%m-%d`.
-pub(crate) fn exif_date(path: &PathBuf) -> Option<(DateSource, u32, u32, u32)> {
+/// - if the `SubSecTimeOriginal` field is present, add it to the time.
+pub(crate) fn exif_date(path: &PathBuf) -> Option<(DateSource, u32, u32, u32, Option<Time>)> {
     std::fs::File::open(path).ok().and_then(|file| {
         let mut bufreader = std::io::BufReader::new(&file);
         exif::Reader::new()
@@ -102,6 +161,18 @@ pub(crate) fn exif_date(path: &PathBuf) -> Option<(DateSource, u32, u32, u32)> {
                     .and_then(|datetimeoriginal| {
                         if let Value::Ascii(text) = &datetimeoriginal.value {
                             let text = String::from_utf8(text[0].clone()).unwrap();
+                            let nanosecond = exif
+                                .get_field(Tag::SubSecTimeOriginal, In::PRIMARY)
+                                .and_then(|subsec| match &subsec.value {
+                                    Value::Ascii(digits) => digits.first().and_then(|digits| {
+                                        let digits = String::from_utf8_lossy(digits);
+                                        let digits = digits.trim();
+                                        (!digits.is_empty()
+                                            && digits.chars().all(|c| c.is_ascii_digit()))
+                                        .then(|| fraction_to_nanoseconds(digits))
+                                    }),
+                                    _ => None,
+                                });
                             let parsed_result =
                                 NaiveDateTime::parse_from_str(&text, "%Y:%m:%d %H:%M:%S");
                             match parsed_result {
@@ -109,13 +180,18 @@ pub(crate) fn exif_date(path: &PathBuf) -> Option<(DateSource, u32, u32, u32)> {
                                     let year = parsed.year() as u32;
                                     let month = parsed.month();
                                     let day = parsed.day();
-                                    Some((DateSource::Exif, year, month, day))
+                                    let time =
+                                        Time::new(parsed.hour(), parsed.minute(), parsed.second())
+                                            .with_nanosecond(nanosecond);
+                                    Some((DateSource::Exif, year, month, day, Some(time)))
                                 }
                                 Err(_) => dateparser::parse(&text).ok().map(|parsed| {
                                     let year = parsed.year() as u32;
                                     let month = parsed.month();
                                     let day = parsed.day();
-                                    (DateSource::Exif, year, month, day)
+                                    let time =
+                                        Time::new(parsed.hour(), parsed.minute(), parsed.second());
+                                    (DateSource::Exif, year, month, day, Some(time))
                                 }),
                             }
                         } else {
@@ -131,7 +207,7 @@ pub(crate) fn exif_date(path: &PathBuf) -> Option<(DateSource, u32, u32, u32)> {
 /// - obtain the file metadata
 /// - get the `created` field
 /// - check whether the created is the same as the current date
-pub(crate) fn metadata_date(path: &PathBuf) -> Option<(DateSource, u32, u32, u32)> {
+pub(crate) fn metadata_date(path: &PathBuf) -> Option<(DateSource, u32, u32, u32, Option<Time>)> {
     std::fs::metadata(path).ok().and_then(|metadata| {
         metadata.created().ok().and_then(|system_time| {
             let today: chrono::DateTime<chrono::offset::Local> = chrono::Local::now();
@@ -141,7 +217,13 @@ pub(crate) fn metadata_date(path: &PathBuf) -> Option<(DateSource, u32, u32, u32
                 let year = creation_date.year() as u32;
                 let month = creation_date.month();
                 let day = creation_date.day();
-                Some((DateSource::Filesystem, year, month, day))
+                let time = Time::new(
+                    creation_date.hour(),
+                    creation_date.minute(),
+                    creation_date.second(),
+                )
+                .with_nanosecond(Some(creation_date.nanosecond()));
+                Some((DateSource::Filesystem, year, month, day, Some(time)))
             } else {
                 None
             }
@@ -157,7 +239,7 @@ mod test {
     #[test]
     fn filename_date1() {
         let file_name = Path::new("An image file from 2024-12-31.jpg");
-        let expected = Some((DateSource::Filename, 2024, 12, 31));
+        let expected = Some((DateSource::Filename, 2024, 12, 31, None));
         let result = filename_date(file_name);
         assert_eq!(expected, result);
     }
@@ -165,7 +247,7 @@ mod test {
     #[test]
     fn filename_date2() {
         let file_name = Path::new("An image file from 20241231.jpg");
-        let expected = Some((DateSource::Filename, 2024, 12, 31));
+        let expected = Some((DateSource::Filename, 2024, 12, 31, None));
         let result = filename_date(file_name);
         assert_eq!(expected, result);
     }
@@ -173,7 +255,50 @@ mod test {
     #[test]
     fn filename_date3() {
         let file_name = Path::new("An image file from 2024-12-01 to 2024-12-31.jpg");
-        let expected = Some((DateSource::Filename, 2024, 12, 1));
+        let expected = Some((DateSource::Filename, 2024, 12, 1, None));
+        let result = filename_date(file_name);
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn filename_datetime1() {
+        let file_name = Path::new("IMG_20240501_142233.jpg");
+        let time = Time::new(14, 22, 33);
+        let expected = Some((DateSource::Filename, 2024, 5, 1, Some(time)));
+        let result = filename_date(file_name);
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn filename_datetime2() {
+        let file_name = Path::new("Screenshot 2024-05-01 at 14.22.33.png");
+        let time = Time::new(14, 22, 33);
+        let expected = Some((DateSource::Filename, 2024, 5, 1, Some(time)));
+        let result = filename_date(file_name);
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn filename_datetime3() {
+        let file_name = Path::new("PXL_20240501_142233123.jpg");
+        let time = Time::new(14, 22, 33).with_nanosecond(Some(123_000_000));
+        let expected = Some((DateSource::Filename, 2024, 5, 1, Some(time)));
+        let result = filename_date(file_name);
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn filename_datetime_needs_separator() {
+        let file_name = Path::new("Scan 2024-05-01142233.jpg");
+        let expected = Some((DateSource::Filename, 2024, 5, 1, None));
+        let result = filename_date(file_name);
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn filename_datetime_ignores_longer_numbers() {
+        let file_name = Path::new("Order 20240501_1422334455667788.pdf");
+        let expected = Some((DateSource::Filename, 2024, 5, 1, None));
         let result = filename_date(file_name);
         assert_eq!(expected, result);
     }
@@ -0,0 +1,188 @@
+//! Highlighting what changed between the old and new paths of entries.
+//!
+//! Paths are compared character by character, finding the longest sequence
+//! of characters they have in common. The characters of the old path not in
+//! it were removed and are shown in red, those of the new path inserted and
+//! shown in green.
+
+use clap::ColorChoice;
+use console::style;
+
+/// Paths whose differing parts would need more comparisons than this are only
+/// compared by their common start and end.
+const MAX_COMPARISONS: usize = 1 << 20;
+
+/// A run of characters the old and new paths have in common, or which were
+/// removed from the old path or inserted into the new one.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Change {
+    Same(String),
+    Removed(String),
+    Inserted(String),
+}
+
+/// Sets whether colors are used on the terminal, or detects it if automatic.
+pub(crate) fn set_color(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Auto => return,
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
+}
+
+/// Returns the changes turning the old text into the new one.
+pub(crate) fn changes(before: &str, after: &str) -> Vec<Change> {
+    let before: Vec<char> = before.chars().collect();
+    let after: Vec<char> = after.chars().collect();
+    let prefix = before
+        .iter()
+        .zip(&after)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = before[prefix..]
+        .iter()
+        .rev()
+        .zip(after[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let removed = &before[prefix..before.len() - suffix];
+    let inserted = &after[prefix..after.len() - suffix];
+
+    let mut changes = Vec::new();
+    push(&mut changes, Change::Same, &before[..prefix]);
+    if removed.len() * inserted.len() > MAX_COMPARISONS {
+        push(&mut changes, Change::Removed, removed);
+        push(&mut changes, Change::Inserted, inserted);
+    } else {
+        compare(&mut changes, removed, inserted);
+    }
+    push(&mut changes, Change::Same, &before[before.len() - suffix..]);
+    changes
+}
+
+/// Appends the changes between the texts, following the longest sequence of
+/// characters they have in common.
+fn compare(changes: &mut Vec<Change>, before: &[char], after: &[char]) {
+    // common[i][j] is the length of the longest common sequence of
+    // before[i..] and after[j..].
+    let mut common = vec![vec![0; after.len() + 1]; before.len() + 1];
+    for i in (0..before.len()).rev() {
+        for j in (0..after.len()).rev() {
+            common[i][j] = if before[i] == after[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < before.len() || j < after.len() {
+        if i < before.len() && j < after.len() && before[i] == after[j] {
+            push(changes, Change::Same, &before[i..=i]);
+            i += 1;
+            j += 1;
+        } else if j == after.len() || (i < before.len() && common[i + 1][j] >= common[i][j + 1]) {
+            push(changes, Change::Removed, &before[i..=i]);
+            i += 1;
+        } else {
+            push(changes, Change::Inserted, &after[j..=j]);
+            j += 1;
+        }
+    }
+}
+
+/// Appends the characters as a change of the given kind, merging them into
+/// the last change if it is of the same kind.
+fn push(changes: &mut Vec<Change>, kind: fn(String) -> Change, chars: &[char]) {
+    if chars.is_empty() {
+        return;
+    }
+    let text: String = chars.iter().collect();
+    match (changes.last_mut(), kind(text)) {
+        (Some(Change::Same(last)), Change::Same(text))
+        | (Some(Change::Removed(last)), Change::Removed(text))
+        | (Some(Change::Inserted(last)), Change::Inserted(text)) => last.push_str(&text),
+        (_, change) => changes.push(change),
+    }
+}
+
+/// Renders the old text, with the characters removed in red.
+pub(crate) fn before(changes: &[Change]) -> String {
+    changes
+        .iter()
+        .map(|change| match change {
+            Change::Same(text) => text.clone(),
+            Change::Removed(text) => style(text).red().to_string(),
+            Change::Inserted(_) => String::new(),
+        })
+        .collect()
+}
+
+/// Renders the new text, with the characters inserted in green.
+pub(crate) fn after(changes: &[Change]) -> String {
+    changes
+        .iter()
+        .map(|change| match change {
+            Change::Same(text) => text.clone(),
+            Change::Removed(_) => String::new(),
+            Change::Inserted(text) => style(text).green().to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn same(text: &str) -> Change {
+        Change::Same(String::from(text))
+    }
+
+    fn removed(text: &str) -> Change {
+        Change::Removed(String::from(text))
+    }
+
+    fn inserted(text: &str) -> Change {
+        Change::Inserted(String::from(text))
+    }
+
+    #[test]
+    fn changes_test() {
+        assert_eq!(vec![same("abc")], changes("abc", "abc"));
+        assert_eq!(Vec::<Change>::new(), changes("", ""));
+        assert_eq!(
+            vec![removed("P"), inserted("p"), same("hoto.JPG")],
+            changes("Photo.JPG", "photo.JPG")
+        );
+        assert_eq!(
+            vec![same("IMG_"), inserted("2024_"), same("001.jpg")],
+            changes("IMG_001.jpg", "IMG_2024_001.jpg")
+        );
+        assert_eq!(
+            vec![
+                same("My"),
+                removed(" "),
+                inserted("_"),
+                same("Song"),
+                removed(" "),
+                inserted("_"),
+                same("(1).mp3")
+            ],
+            changes("My Song (1).mp3", "My_Song_(1).mp3")
+        );
+        assert_eq!(
+            vec![same("a"), removed("ñ"), inserted("n"), same("o")],
+            changes("año", "ano")
+        );
+    }
+
+    #[test]
+    fn render_without_color() {
+        console::set_colors_enabled(false);
+        let changes = changes("My Song.mp3", "my_song.mp3");
+        assert_eq!("My Song.mp3", before(&changes));
+        assert_eq!("my_song.mp3", after(&changes));
+    }
+}
@@ -0,0 +1,219 @@
+//! Splitting file names into a stem and an extension.
+//!
+//! `Path::file_stem` and `Path::extension` only consider the last dot-separated
+//! component to be the extension, so `archive.tar.gz` has the stem
+//! `archive.tar`. The splitter may instead recognize compound extensions, or
+//! treat everything after the first dot as the extension.
+
+use clap::ValueEnum;
+use glob::MatchOptions;
+use glob::Pattern;
+use serde::Deserialize;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Compound extensions recognized by default. Each one is a glob pattern
+/// matched, ignoring case, against the trailing components of a file name.
+pub(crate) const DEFAULT_COMPOUND_EXTENSIONS: &[&str] = &[
+    "tar.gz",
+    "tar.bz2",
+    "tar.xz",
+    "tar.zst",
+    "tar.lz",
+    "tar.lzma",
+    "[a-z][a-z].srt",
+    "[a-z][a-z].sub",
+    "[a-z][a-z].ass",
+    "[a-z][a-z].vtt",
+];
+
+/// Which dot-separated components of a file name make up its extension.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExtensionMode {
+    /// Only the last component.
+    Last,
+    /// The longest chain of trailing components matching a compound extension,
+    /// or else the last component.
+    Compound,
+    /// All the components after the first one.
+    All,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct ExtensionSplitter {
+    mode: ExtensionMode,
+    compound: Vec<Pattern>,
+}
+
+impl Default for ExtensionSplitter {
+    fn default() -> Self {
+        ExtensionSplitter::new(ExtensionMode::Compound)
+    }
+}
+
+impl ExtensionSplitter {
+    pub(crate) fn new(mode: ExtensionMode) -> Self {
+        ExtensionSplitter {
+            mode,
+            compound: DEFAULT_COMPOUND_EXTENSIONS
+                .iter()
+                .map(|pattern| Pattern::new(pattern).unwrap())
+                .collect(),
+        }
+    }
+
+    /// Adds compound extensions to the default ones.
+    pub(crate) fn with_compound(
+        mut self,
+        extensions: &[String],
+    ) -> Result<Self, glob::PatternError> {
+        for extension in extensions {
+            self.compound
+                .push(Pattern::new(extension.trim_start_matches('.'))?);
+        }
+        Ok(self)
+    }
+
+    /// Splits a file name into a stem and an extension, without the dot.
+    /// The extension is empty if there is none. A leading dot, as in hidden
+    /// files, does not start an extension.
+    pub(crate) fn split<'a>(&self, name: &'a str) -> (&'a str, &'a str) {
+        let dots: Vec<usize> = name
+            .char_indices()
+            .filter(|(index, c)| *c == '.' && *index > 0)
+            .map(|(index, _)| index)
+            .collect();
+        let dot = match self.mode {
+            ExtensionMode::Last => dots.last().copied(),
+            ExtensionMode::All => dots.first().copied(),
+            // Only chains of two or more components may be compound, longest first.
+            ExtensionMode::Compound => dots[..dots.len().saturating_sub(1)]
+                .iter()
+                .copied()
+                .find(|dot| self.is_compound(&name[dot + 1..]))
+                .or(dots.last().copied()),
+        };
+        match dot {
+            Some(dot) => (&name[..dot], &name[dot + 1..]),
+            None => (name, ""),
+        }
+    }
+
+    fn is_compound(&self, extension: &str) -> bool {
+        let options = MatchOptions {
+            case_sensitive: false,
+            ..MatchOptions::new()
+        };
+        self.compound
+            .iter()
+            .any(|pattern| pattern.matches_with(extension, options))
+    }
+
+    /// Returns the stem of the file name, which is empty if the name is not
+    /// valid UTF-8.
+    pub(crate) fn stem<'a>(&self, path: &'a Path) -> &'a str {
+        self.split(file_name(path).unwrap_or_default()).0
+    }
+
+    /// Returns the extension of the file name, which is empty if the name is
+    /// not valid UTF-8.
+    pub(crate) fn extension<'a>(&self, path: &'a Path) -> &'a str {
+        self.split(file_name(path).unwrap_or_default()).1
+    }
+
+    /// Replaces the stem of the path, keeping its extension. Names that are
+    /// not valid UTF-8 are left unchanged.
+    pub(crate) fn set_stem(&self, path: &mut PathBuf, stem: &str) {
+        if file_name(path).is_some() {
+            let extension = self.extension(path).to_string();
+            path.set_file_name(join(stem, &extension));
+        }
+    }
+
+    /// Replaces the extension of the path, or removes it if the new one is
+    /// empty. Names that are not valid UTF-8 are left unchanged.
+    pub(crate) fn set_extension(&self, path: &mut PathBuf, extension: &str) {
+        if file_name(path).is_some() {
+            let stem = self.stem(path).to_string();
+            path.set_file_name(join(&stem, extension.trim_start_matches('.')));
+        }
+    }
+}
+
+fn file_name(path: &Path) -> Option<&str> {
+    path.file_name().unwrap_or_default().to_str()
+}
+
+fn join(stem: &str, extension: &str) -> String {
+    if extension.is_empty() {
+        String::from(stem)
+    } else {
+        format!("{stem}.{extension}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    macro_rules! test {
+        ($t:ident : $s1:expr => $s2:expr) => {
+            #[test]
+            fn $t() {
+                assert_eq!($s1, $s2)
+            }
+        };
+    }
+
+    fn split(mode: ExtensionMode, name: &str) -> (&str, &str) {
+        ExtensionSplitter::new(mode).split(name)
+    }
+
+    test!(split_last_test:
+        split(ExtensionMode::Last, "archive.tar.gz") => ("archive.tar", "gz"));
+    test!(split_compound_test:
+        split(ExtensionMode::Compound, "archive.tar.gz") => ("archive", "tar.gz"));
+    test!(split_compound_case_test:
+        split(ExtensionMode::Compound, "Archive.TAR.GZ") => ("Archive", "TAR.GZ"));
+    test!(split_compound_subtitle_test:
+        split(ExtensionMode::Compound, "Some.Movie.2019.en.srt") => ("Some.Movie.2019", "en.srt"));
+    test!(split_compound_fallback_test:
+        split(ExtensionMode::Compound, "Some.Movie.2019.srt") => ("Some.Movie.2019", "srt"));
+    test!(split_all_test:
+        split(ExtensionMode::All, "Some.Movie.2019.srt") => ("Some", "Movie.2019.srt"));
+    test!(split_hidden_test:
+        split(ExtensionMode::All, ".hidden") => (".hidden", ""));
+    test!(split_none_test:
+        split(ExtensionMode::Compound, "README") => ("README", ""));
+    test!(split_custom_compound_test:
+        ExtensionSplitter::new(ExtensionMode::Compound)
+            .with_compound(&[String::from(".min.js")])
+            .unwrap()
+            .split("app.min.js") => ("app", "min.js"));
+
+    #[test]
+    fn set_stem_and_extension() {
+        let splitter = ExtensionSplitter::default();
+        let mut path = PathBuf::from("dir/Archive.tar.gz");
+        splitter.set_stem(&mut path, "archive");
+        assert_eq!(PathBuf::from("dir/archive.tar.gz"), path);
+        splitter.set_extension(&mut path, "tgz");
+        assert_eq!(PathBuf::from("dir/archive.tgz"), path);
+        splitter.set_extension(&mut path, "");
+        assert_eq!(PathBuf::from("dir/archive"), path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn name_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let splitter = ExtensionSplitter::default();
+        let name = OsStr::from_bytes(b"caf\xe9.jpg");
+        let mut path = PathBuf::from("dir").join(name);
+        assert_eq!("", splitter.extension(&path));
+        splitter.set_extension(&mut path, "png");
+        assert_eq!(Some(name), path.file_name());
+    }
+}
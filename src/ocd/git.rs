@@ -0,0 +1,391 @@
+//! Keeping git's index in step with renamed files.
+//!
+//! Entries are renamed on the filesystem like any others, and the renames of
+//! tracked files are then recorded in the index of the work tree they belong
+//! to, moving each index entry to the new path in a single git call per work
+//! tree, so that changes not yet staged stay unstaged. This is what `git mv`
+//! does, but it also works for case-only renames on case-insensitive
+//! filesystems. Untracked files, and files outside any work tree, are simply
+//! renamed.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+/// The number of paths given to each git invocation.
+const BATCH_SIZE: usize = 256;
+
+/// The work trees containing the entries of a plan, and their tracked files.
+pub(crate) struct GitIndex {
+    /// The tracked files of each work tree, by absolute path, with the mode
+    /// and object of their index entry unless they are unmerged.
+    tracked: BTreeMap<PathBuf, HashMap<PathBuf, Option<String>>>,
+    /// The work tree containing each directory visited, if any.
+    work_trees: HashMap<PathBuf, Option<PathBuf>>,
+}
+
+impl GitIndex {
+    pub(crate) fn new() -> Self {
+        GitIndex {
+            tracked: BTreeMap::new(),
+            work_trees: HashMap::new(),
+        }
+    }
+
+    /// Returns the tracked files at or below the path, each by its absolute
+    /// path and by its path relative to the given one.
+    pub(crate) fn tracked_files(
+        &mut self,
+        path: &Path,
+    ) -> Result<Vec<(PathBuf, PathBuf)>, Box<dyn Error>> {
+        let absolute_path = absolute(path)?;
+        let parent = absolute_path.parent().unwrap_or(&absolute_path);
+        let work_tree = match self.work_tree(parent)? {
+            Some(work_tree) => work_tree,
+            None => return Ok(Vec::new()),
+        };
+        let mut files: Vec<(PathBuf, PathBuf)> = self.tracked[&work_tree]
+            .keys()
+            .filter_map(|file| {
+                let relative = file.strip_prefix(&absolute_path).ok()?;
+                let path = if relative.as_os_str().is_empty() {
+                    path.to_path_buf()
+                } else {
+                    path.join(relative)
+                };
+                Some((file.clone(), path))
+            })
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /// Records that the tracked files were renamed, given pairs of their
+    /// absolute old paths and their new paths, which must exist. The index
+    /// entry is moved to the new path, as the file was when last staged.
+    /// Unmerged files, and files moved to another work tree, are removed and
+    /// added again instead.
+    pub(crate) fn record_renames(
+        &mut self,
+        renames: &[(PathBuf, PathBuf)],
+    ) -> Result<(), Box<dyn Error>> {
+        let mut removed: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        let mut added: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        let mut moved: BTreeMap<PathBuf, (Vec<u8>, Vec<u8>)> = BTreeMap::new();
+        for (old, new) in renames {
+            let new = absolute(new)?;
+            let new_work_tree = self.work_tree(new.parent().unwrap_or(&new))?;
+            let old_work_tree = self.containing(old);
+            let entry = old_work_tree
+                .as_ref()
+                .and_then(|work_tree| self.tracked[work_tree].get(old).cloned().flatten());
+            match (old_work_tree, new_work_tree, entry) {
+                (Some(old_work_tree), Some(new_work_tree), Some(entry))
+                    if old_work_tree == new_work_tree =>
+                {
+                    let (removals, additions) = moved.entry(old_work_tree.clone()).or_default();
+                    let object = entry.split(' ').nth(1).unwrap_or_default();
+                    index_info(removals, "0", object, old.strip_prefix(&old_work_tree)?);
+                    let (mode, object) = entry.split_once(' ').unwrap_or_default();
+                    index_info(additions, mode, object, new.strip_prefix(&new_work_tree)?);
+                }
+                (old_work_tree, new_work_tree, _) => {
+                    if let Some(work_tree) = old_work_tree {
+                        let relative = old.strip_prefix(&work_tree)?.to_path_buf();
+                        removed.entry(work_tree).or_default().push(relative);
+                    }
+                    if let Some(work_tree) = new_work_tree {
+                        let relative = new.strip_prefix(&work_tree)?.to_path_buf();
+                        added.entry(work_tree).or_default().push(relative);
+                    }
+                }
+            }
+        }
+        // Removing first lets a case-only rename be added back on
+        // case-insensitive filesystems.
+        for (work_tree, paths) in removed {
+            run_batched(&work_tree, &["rm", "--cached", "--quiet", "--"], &paths)?;
+        }
+        for (work_tree, (mut removals, additions)) in moved {
+            removals.extend(additions);
+            update_index(&work_tree, &removals)?;
+        }
+        for (work_tree, paths) in added {
+            run_batched(&work_tree, &["add", "--force", "--"], &paths)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the file is tracked.
+    pub(crate) fn is_tracked(&mut self, path: &Path) -> Result<bool, Box<dyn Error>> {
+        let path = absolute(path)?;
+        Ok(self
+            .work_tree(path.parent().unwrap_or(&path))?
+            .is_some_and(|work_tree| {
+                self.tracked[&work_tree]
+                    .keys()
+                    .any(|file| file.starts_with(&path))
+            }))
+    }
+
+    /// Returns the loaded work tree containing the absolute path, if any.
+    fn containing(&self, path: &Path) -> Option<PathBuf> {
+        self.tracked
+            .keys()
+            .filter(|work_tree| path.starts_with(work_tree))
+            .max_by_key(|work_tree| work_tree.components().count())
+            .cloned()
+    }
+
+    /// Returns the top of the work tree containing the directory, loading its
+    /// tracked files the first time it is seen.
+    fn work_tree(&mut self, dir: &Path) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        if let Some(work_tree) = self.work_trees.get(dir) {
+            return Ok(work_tree.clone());
+        }
+        let output = git(dir, &["rev-parse", "--show-toplevel"])?;
+        let work_tree = if output.status.success() {
+            let top = output.stdout.strip_suffix(b"\n").unwrap_or(&output.stdout);
+            Some(path_from_bytes(top))
+        } else {
+            None
+        };
+        if let Some(work_tree) = &work_tree {
+            if !self.tracked.contains_key(work_tree) {
+                let tracked = ls_files(work_tree)?;
+                self.tracked.insert(work_tree.clone(), tracked);
+            }
+        }
+        self.work_trees.insert(dir.to_path_buf(), work_tree.clone());
+        Ok(work_tree)
+    }
+}
+
+/// Returns the absolute path of an entry, resolving symbolic links in its
+/// parent directories but not in the entry itself.
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let parent = fs::canonicalize(parent)?;
+    Ok(match path.file_name() {
+        Some(name) => parent.join(name),
+        None => parent,
+    })
+}
+
+/// Lists the tracked files of the work tree, by absolute path, with the mode
+/// and object of their index entry unless they are unmerged.
+fn ls_files(work_tree: &Path) -> Result<HashMap<PathBuf, Option<String>>, Box<dyn Error>> {
+    let output = git(work_tree, &["ls-files", "--stage", "-z"])?;
+    if !output.status.success() {
+        return Err(git_error(&["ls-files"], &output.stderr));
+    }
+    let mut files = HashMap::new();
+    for (file, entry) in parse_ls_files(&output.stdout).filter_map(parse_stage) {
+        files.insert(work_tree.join(path_from_bytes(file)), entry);
+    }
+    Ok(files)
+}
+
+/// Splits a line of `git ls-files --stage` into the path and, unless it is an
+/// unmerged stage, the mode and object of the entry.
+fn parse_stage(line: &[u8]) -> Option<(&[u8], Option<String>)> {
+    let tab = line.iter().position(|&byte| byte == b'\t')?;
+    let (entry, file) = (std::str::from_utf8(&line[..tab]).ok()?, &line[tab + 1..]);
+    let mut fields = entry.split(' ');
+    let (mode, object, stage) = (fields.next()?, fields.next()?, fields.next()?);
+    Some((file, (stage == "0").then(|| format!("{mode} {object}"))))
+}
+
+/// Appends a line of `git update-index --index-info` input, NUL-terminated.
+fn index_info(input: &mut Vec<u8>, mode: &str, object: &str, path: &Path) {
+    input.extend_from_slice(format!("{mode} {object}\t").as_bytes());
+    input.extend_from_slice(&path_to_bytes(path));
+    input.push(0);
+}
+
+/// Updates the index of the work tree with the `--index-info` input.
+fn update_index(work_tree: &Path, input: &[u8]) -> Result<(), Box<dyn Error>> {
+    let args = ["update-index", "-z", "--index-info"];
+    let mut child = command(work_tree)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(not_available)?;
+    child.stdin.take().unwrap().write_all(input)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(git_error(&args, &output.stderr));
+    }
+    Ok(())
+}
+
+fn parse_ls_files(output: &[u8]) -> impl Iterator<Item = &[u8]> {
+    output
+        .split(|&byte| byte == 0)
+        .filter(|file| !file.is_empty())
+}
+
+/// Returns the path git printed, which need not be valid UTF-8.
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Returns the path as git expects it, which need not be valid UTF-8.
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().replace('\\', "/").into_bytes()
+}
+
+/// Runs a git command on the paths, a batch at a time.
+fn run_batched(work_tree: &Path, args: &[&str], paths: &[PathBuf]) -> Result<(), Box<dyn Error>> {
+    for batch in paths.chunks(BATCH_SIZE) {
+        let output = command(work_tree)
+            .args(args)
+            .args(batch)
+            .output()
+            .map_err(not_available)?;
+        if !output.status.success() {
+            return Err(git_error(args, &output.stderr));
+        }
+    }
+    Ok(())
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<std::process::Output, Box<dyn Error>> {
+    command(dir).args(args).output().map_err(not_available)
+}
+
+/// Returns a git command run in the directory. Paths given to it are taken
+/// literally, not as patterns, so that e.g. `Song [Live].mp3` does not also
+/// match `Song L.mp3`.
+fn command(dir: &Path) -> Command {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let mut command = Command::new("git");
+    command.arg("-C").arg(dir).env("GIT_LITERAL_PATHSPECS", "1");
+    command
+}
+
+fn not_available(err: io::Error) -> Box<dyn Error> {
+    format!("Unable to run git: {err}").into()
+}
+
+fn git_error(args: &[&str], stderr: &[u8]) -> Box<dyn Error> {
+    format!(
+        "git {} failed: {}",
+        args.first().unwrap_or(&""),
+        String::from_utf8_lossy(stderr).trim()
+    )
+    .into()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    #[test]
+    fn parse_ls_files_output() {
+        let files: Vec<&[u8]> = parse_ls_files(b"a.jpg\0dir/b c.jpg\0caf\xe9.jpg\0").collect();
+        assert_eq!(vec![&b"a.jpg"[..], b"dir/b c.jpg", b"caf\xe9.jpg"], files);
+        assert_eq!(0, parse_ls_files(b"").count());
+    }
+
+    /// Creates a repository in a new directory, with the files staged.
+    pub(crate) fn init_repository(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ocd-git-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        assert!(git(&dir, &["init", "--quiet"]).unwrap().status.success());
+        for file in files {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        let mut args = vec!["add", "--"];
+        args.extend(files);
+        assert!(git(&dir, &args).unwrap().status.success());
+        fs::canonicalize(dir).unwrap()
+    }
+
+    /// Lists the files in the index of the repository.
+    pub(crate) fn staged(dir: &Path) -> Vec<String> {
+        let output = git(dir, &["ls-files", "-z"]).unwrap();
+        parse_ls_files(&output.stdout)
+            .map(|file| String::from_utf8_lossy(file).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn record_renames_literally() {
+        let dir = init_repository("literal", &["Song [Live].mp3", "Song L.mp3"]);
+        fs::write(dir.join("Song i.mp3"), "untracked").unwrap();
+        fs::rename(dir.join("Song [Live].mp3"), dir.join("Song (Live).mp3")).unwrap();
+        let mut index = GitIndex::new();
+        let renames = index.tracked_files(&dir.join("Song [Live].mp3")).unwrap();
+        assert_eq!(1, renames.len());
+        let renames = vec![(renames[0].0.clone(), dir.join("Song (Live).mp3"))];
+        index.record_renames(&renames).unwrap();
+        assert_eq!(vec!["Song (Live).mp3", "Song L.mp3"], staged(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_stage_entries() {
+        let object = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+        let line = format!("100644 {object} 0\tdir/a b.jpg");
+        assert_eq!(
+            Some((&b"dir/a b.jpg"[..], Some(format!("100644 {object}")))),
+            parse_stage(line.as_bytes())
+        );
+        let line = format!("100644 {object} 2\ta.jpg");
+        assert_eq!(Some((&b"a.jpg"[..], None)), parse_stage(line.as_bytes()));
+    }
+
+    #[test]
+    fn record_renames_keeps_changes_unstaged() {
+        let dir = init_repository("unstaged", &["a.txt"]);
+        fs::write(dir.join("a.txt"), "edited").unwrap();
+        fs::rename(dir.join("a.txt"), dir.join("b.txt")).unwrap();
+        let mut index = GitIndex::new();
+        index.tracked_files(&dir.join("a.txt")).unwrap();
+        let renames = vec![(dir.join("a.txt"), dir.join("b.txt"))];
+        index.record_renames(&renames).unwrap();
+        assert_eq!(vec!["b.txt"], staged(&dir));
+        let output = git(&dir, &["diff", "--name-only"]).unwrap();
+        assert_eq!("b.txt\n", String::from_utf8_lossy(&output.stdout));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn absolute_keeps_file_name() {
+        let path = absolute(Path::new("Cargo.toml")).unwrap();
+        assert!(path.is_absolute());
+        assert_eq!(Some("Cargo.toml".as_ref()), path.file_name());
+    }
+}
@@ -1,25 +1,46 @@
 //! Main OCD module.
+mod case;
+pub(crate) mod config;
 mod date;
+mod diff;
+mod extension;
+mod git;
 pub(crate) mod mrn;
+mod order;
+mod portable;
+mod progress;
+mod review;
+mod transfer;
 pub(crate) mod tss;
+mod walk;
 
+use crate::ocd::case::CaseProbe;
 use crate::ocd::date::DateSource;
+use crate::ocd::extension::ExtensionSplitter;
+use crate::ocd::git::GitIndex;
+use crate::ocd::order::Order;
+use crate::ocd::portable::Portability;
+use crate::ocd::transfer::Transfer;
 use clap::Parser;
 use clap::Subcommand;
 use clap::ValueEnum;
 use dialoguer::Confirm;
 use dialoguer::Input;
+use serde::Deserialize;
+use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::collections::HashSet;
 use std::error::Error;
 use std::fmt;
 use std::fmt::Display;
+use std::fmt::Write as _;
 use std::fs;
 use std::io;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
-use std::process::Command;
 
 /// The command line interface configuration.
 #[derive(Debug, Parser)]
@@ -58,13 +79,47 @@ pub enum OcdCommand {
 }
 
 /// File processing mode, filters only regular files, only directories, or both.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum Mode {
     All,
     Directories,
     Files,
 }
 
+/// Whether the user confirms the whole plan at once, or each action in turn.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Confirmation {
+    All,
+    Each,
+}
+
+/// An answer when confirming each action: execute it, skip it, execute it and
+/// all the remaining ones, skip it and all the remaining ones, or edit its
+/// destination and execute it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Answer {
+    Yes,
+    No,
+    All,
+    Quit,
+    Edit,
+}
+
+impl Answer {
+    fn parse(input: &str) -> Option<Answer> {
+        match input.trim().to_lowercase().as_str() {
+            "y" | "yes" => Some(Answer::Yes),
+            "n" | "no" => Some(Answer::No),
+            "a" | "all" => Some(Answer::All),
+            "q" | "quit" => Some(Answer::Quit),
+            "e" | "edit" => Some(Answer::Edit),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum Verbosity {
     Silent,
@@ -88,6 +143,11 @@ impl Verbosity {
     fn is_silent(&self) -> bool {
         matches!(self, Verbosity::Silent)
     }
+
+    /// Progress bars are shown unless silent, or printing debug information.
+    fn shows_progress(&self) -> bool {
+        !matches!(self, Verbosity::Silent | Verbosity::Debug)
+    }
 }
 
 impl fmt::Display for Verbosity {
@@ -108,25 +168,103 @@ trait Speaker {
     fn verbosity(&self) -> Verbosity;
 }
 
-/// An action on a file can be either move the file to a new directory,
-/// or rename the file.
+/// An action on a file can be either move, copy or link the file into a new
+/// directory, or rename the file.
 /// The date source included in the Move variant is a bit of a hack.
 /// It is intended to help track where the date was obtained from, and should
 /// probably either be a field in a struct that wraps this enum, or be present
 /// in both variants. Since at this time, the date source is only tracked for
 /// the `Move` variant which is only used in the Time Stamp Sorted utility, it
 /// is inluded there.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 enum Action {
     Move {
         date_source: Option<DateSource>,
         path: PathBuf,
     },
+    Copy {
+        date_source: Option<DateSource>,
+        path: PathBuf,
+    },
+    Hardlink {
+        date_source: Option<DateSource>,
+        path: PathBuf,
+    },
+    Symlink {
+        date_source: Option<DateSource>,
+        path: PathBuf,
+    },
+    Reflink {
+        date_source: Option<DateSource>,
+        path: PathBuf,
+    },
     Rename {
         path: PathBuf,
     },
 }
 
+impl Action {
+    /// The destination path of the action, which for all but renames is the
+    /// directory the file is placed into.
+    fn path(&self) -> &PathBuf {
+        match self {
+            Action::Move { path, .. }
+            | Action::Copy { path, .. }
+            | Action::Hardlink { path, .. }
+            | Action::Symlink { path, .. }
+            | Action::Reflink { path, .. }
+            | Action::Rename { path } => path,
+        }
+    }
+
+    fn path_mut(&mut self) -> &mut PathBuf {
+        match self {
+            Action::Move { path, .. }
+            | Action::Copy { path, .. }
+            | Action::Hardlink { path, .. }
+            | Action::Symlink { path, .. }
+            | Action::Reflink { path, .. }
+            | Action::Rename { path } => path,
+        }
+    }
+
+    fn date_source(&self) -> Option<&DateSource> {
+        match self {
+            Action::Move { date_source, .. }
+            | Action::Copy { date_source, .. }
+            | Action::Hardlink { date_source, .. }
+            | Action::Symlink { date_source, .. }
+            | Action::Reflink { date_source, .. } => date_source.as_ref(),
+            Action::Rename { .. } => None,
+        }
+    }
+
+    /// Returns the name of the action, and how its destination is described.
+    fn describe(&self) -> (&str, &str) {
+        match self {
+            Action::Move { .. } => ("move", "moved to"),
+            Action::Copy { .. } => ("copy", "copied to"),
+            Action::Hardlink { .. } => ("hardlink", "hard linked in"),
+            Action::Symlink { .. } => ("symlink", "symlinked in"),
+            Action::Reflink { .. } => ("reflink", "reflinked to"),
+            Action::Rename { .. } => ("rename", "renamed to"),
+        }
+    }
+
+    /// Returns true if the action takes the file away from its source.
+    fn vacates(&self) -> bool {
+        matches!(self, Action::Move { .. } | Action::Rename { .. })
+    }
+
+    /// Returns the full destination path of the file.
+    fn destination(&self, src: &Path) -> PathBuf {
+        match self {
+            Action::Rename { path } => path.clone(),
+            _ => self.path().join(src.file_name().unwrap_or_default()),
+        }
+    }
+}
+
 impl Display for Action {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -140,10 +278,17 @@ impl Display for Action {
 /// created (to include deletion instructions in an undo file), whether or not
 /// git is to be used to perform actions on the filesystem, and string lengths
 /// for presentation.
+#[derive(Clone)]
 struct Plan {
     pub actions: BTreeMap<PathBuf, Action>,
+    /// The entries left unchanged, with the reason why.
+    skipped: BTreeMap<PathBuf, String>,
+    /// The position of each source in listings, if they are not listed by path.
+    ranks: HashMap<PathBuf, usize>,
     dirs: HashSet<PathBuf>,
     use_git: bool,
+    transfer: Transfer,
+    progress: bool,
     max_src_len: usize,
     max_dst_len: usize,
 }
@@ -153,7 +298,11 @@ impl Plan {
         Plan {
             dirs: HashSet::new(),
             actions: BTreeMap::new(),
+            skipped: BTreeMap::new(),
+            ranks: HashMap::new(),
             use_git: false,
+            transfer: Transfer::new(),
+            progress: false,
             max_src_len: 0,
             max_dst_len: 0,
         }
@@ -164,6 +313,17 @@ impl Plan {
         self
     }
 
+    fn with_transfer(mut self, transfer: Transfer) -> Self {
+        self.transfer = transfer;
+        self
+    }
+
+    /// Shows the progress of executing the plan.
+    fn with_progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
     fn with_files(mut self, files: Vec<PathBuf>) -> Self {
         for file in files {
             self.insert(file.clone(), Action::Rename { path: file.clone() });
@@ -171,139 +331,629 @@ impl Plan {
         self
     }
 
+    /// Lists the entries in the order, reading their dates or sizes on the
+    /// given number of worker threads.
+    fn sort_by(&mut self, order: Order, jobs: usize) -> Result<(), Box<dyn Error>> {
+        let sources = self.actions.keys().cloned().collect();
+        let sorted = with_jobs(jobs, || order::sort(sources, order))?;
+        self.ranks = sorted
+            .into_iter()
+            .enumerate()
+            .map(|(rank, src)| (src, rank))
+            .collect();
+        Ok(())
+    }
+
+    /// Returns the actions in the order they are listed, that of `sort_by`,
+    /// or else by source path.
+    fn listed(&self) -> Vec<(&PathBuf, &Action)> {
+        let mut actions: Vec<(&PathBuf, &Action)> = self.actions.iter().collect();
+        actions.sort_by_key(|(src, _)| self.ranks.get(*src).copied().unwrap_or(usize::MAX));
+        actions
+    }
+
     /// Removes all actions in plan which would result in the file being renamed
     /// into itself or moved into the current directory.
     fn clean(&mut self) {
         // Retains only the elements specified by the predicate.
         // In other words, remove all pairs for which the predicate returns false.
         self.actions.retain(|src, action| match action {
-            Action::Move { .. } => true,
             Action::Rename { path } => src != path,
+            _ => true,
         })
     }
 
+    /// Remembers the directories missing from the destinations of renames,
+    /// which will be created when the plan is executed, so that the undo
+    /// script can remove them.
+    fn record_missing_dirs(&mut self) {
+        for action in self.actions.values() {
+            if let Action::Rename { path } = action {
+                for dir in path.ancestors().skip(1) {
+                    if dir.as_os_str().is_empty() || dir.exists() {
+                        break;
+                    }
+                    self.dirs.insert(dir.to_path_buf());
+                }
+            }
+        }
+    }
+
+    /// Keeps only the actions on the sources accepted, recomputing the
+    /// directories created and the lengths used for presentation.
+    fn retain(&mut self, accept: impl Fn(&Path) -> bool) {
+        let actions = std::mem::take(&mut self.actions);
+        self.dirs.clear();
+        self.max_src_len = 0;
+        self.max_dst_len = 0;
+        for (src, action) in actions {
+            if accept(&src) {
+                self.insert(src, action);
+            }
+        }
+        self.record_missing_dirs();
+    }
+
     fn insert(&mut self, src: PathBuf, action: Action) {
         let path = match action {
-            Action::Move { ref path, .. } => {
-                // In the case of a move, the program will have created a
-                // directory into which the file will be moved, and it must be
-                // remembered so that the undo script can remove it.
-                self.dirs.insert(path.clone());
-                path
-            }
             Action::Rename { ref path } => path,
+            _ => {
+                // In the case of a move, copy or link, the program will have
+                // created a directory into which the file will be placed, and
+                // it must be remembered so that the undo script can remove it.
+                self.dirs.insert(action.path().clone());
+                action.path()
+            }
         };
 
-        // Maximum source character length
-        let msl = path_length(&src);
+        // Maximum source display width
+        let msl = path_width(&src);
         if msl > self.max_src_len {
             self.max_src_len = msl
         }
-        // Maximum destination character length
-        let mdl = path_length(path);
+        // Maximum destination display width
+        let mdl = path_width(path);
         if mdl > self.max_dst_len {
             self.max_dst_len = mdl
         }
         self.actions.insert(src, action);
     }
 
-    fn present_short(&self) {
-        let msl = self.max_src_len;
-        let mdl = self.max_dst_len;
-        for (src, action) in &self.actions {
-            match action {
-                Action::Move { path, .. } => {
-                    println!("{:<msl$} moved to {:<mdl$}", src.display(), path.display(),);
+    /// Asks the user whether to execute each action in turn, possibly with an
+    /// edited destination, and keeps only those accepted. Quitting keeps the
+    /// actions accepted so far.
+    fn confirm_each(mut self) -> Plan {
+        let sources: Vec<PathBuf> = self
+            .listed()
+            .into_iter()
+            .map(|(src, _)| src.clone())
+            .collect();
+        let mut accepted = HashSet::new();
+        let mut all = false;
+        for (index, src) in sources.into_iter().enumerate() {
+            if all {
+                accepted.insert(src);
+                continue;
+            }
+            let action = &self.actions[&src];
+            let (_, description) = action.describe();
+            println!(
+                "({}/{}) {} {} {}",
+                index + 1,
+                self.actions.len(),
+                display_path(&src),
+                description,
+                display_path(&self.final_path(action.path()))
+            );
+            match user_answer() {
+                Answer::Yes => {}
+                Answer::No => continue,
+                Answer::All => all = true,
+                Answer::Quit => break,
+                Answer::Edit => {
+                    let path = user_edit(action.path());
+                    if let Some(action) = self.actions.get_mut(&src) {
+                        *action.path_mut() = path;
+                    }
                 }
-                Action::Rename { path } => {
-                    println!(
-                        "{:<msl$} renamed to {:<mdl$}",
-                        src.display(),
-                        path.display(),
-                    );
+            }
+            accepted.insert(src);
+        }
+        self.retain(|src| accepted.contains(src));
+        self
+    }
+
+    /// Returns the actions in the order they must be executed: entries inside
+    /// a directory before the directory itself, so that renaming it does not
+    /// invalidate their paths, and an entry before the one taking its place.
+    /// Otherwise deeper entries go first. Entries taking each other's places
+    /// in a cycle are broken out of it by a rename to a temporary name.
+    fn ordered(&self) -> Vec<(PathBuf, Action)> {
+        let mut sources: Vec<&PathBuf> = self.actions.keys().collect();
+        sources.sort_by_key(|src| std::cmp::Reverse(src.components().count()));
+        let count = sources.len();
+        let mut probe = CaseProbe::new();
+        let indices: HashMap<&Path, usize> = sources
+            .iter()
+            .enumerate()
+            .map(|(i, src)| (src.as_path(), i))
+            .collect();
+        let vacated: HashMap<String, usize> = sources
+            .iter()
+            .enumerate()
+            .filter(|(_, src)| self.actions[**src].vacates())
+            .map(|(i, src)| (probe.collision_key(src), i))
+            .collect();
+        // The number of entries each entry waits for, and the entries waiting
+        // for each entry to leave its place, or to be executed.
+        let mut waiting = vec![0; count];
+        let mut for_place: Vec<Vec<usize>> = vec![Vec::new(); count];
+        let mut for_execution: Vec<Vec<usize>> = vec![Vec::new(); count];
+        for (i, src) in sources.iter().enumerate() {
+            let dst = self.actions[*src].destination(src);
+            if let Some(&j) = vacated.get(&probe.collision_key(&dst)) {
+                if j != i {
+                    waiting[i] += 1;
+                    for_place[j].push(i);
+                }
+            }
+            for dir in src.ancestors().skip(1) {
+                if let Some(&j) = indices.get(dir) {
+                    if self.actions[sources[j]].vacates() {
+                        waiting[j] += 1;
+                        for_execution[i].push(j);
+                    }
                 }
             }
         }
+        let mut ready: BTreeSet<usize> = (0..count).filter(|&i| waiting[i] == 0).collect();
+        let mut temporary: HashMap<usize, PathBuf> = HashMap::new();
+        let mut done = vec![false; count];
+        let mut ordered = Vec::with_capacity(count);
+        while ordered.len() < count + temporary.len() {
+            let Some(i) = ready.pop_first() else {
+                // Every entry left waits for another, so one is moved out of
+                // the way of those waiting for its place.
+                let pending = |j: &usize| !done[*j] && !temporary.contains_key(j);
+                match (0..count).find(|j| pending(j) && !for_place[*j].is_empty()) {
+                    Some(j) => {
+                        let path = case::temporary_path(sources[j]);
+                        let action = Action::Rename { path: path.clone() };
+                        ordered.push((sources[j].clone(), action));
+                        temporary.insert(j, path);
+                        for k in std::mem::take(&mut for_place[j]) {
+                            waiting[k] -= 1;
+                            if waiting[k] == 0 {
+                                ready.insert(k);
+                            }
+                        }
+                    }
+                    None => {
+                        // Unreachable, since directories cannot contain each
+                        // other, but the remaining entries are executed anyway.
+                        ready.extend((0..count).filter(|j| !done[*j]));
+                    }
+                }
+                continue;
+            };
+            if done[i] {
+                continue;
+            }
+            done[i] = true;
+            let action = &self.actions[sources[i]];
+            ordered.push(match temporary.get(&i) {
+                Some(path) => {
+                    let dst = action.destination(sources[i]);
+                    (path.clone(), Action::Rename { path: dst })
+                }
+                None => (sources[i].clone(), action.clone()),
+            });
+            let waiters = std::mem::take(&mut for_place[i]);
+            for k in waiters
+                .into_iter()
+                .chain(std::mem::take(&mut for_execution[i]))
+            {
+                waiting[k] -= 1;
+                if waiting[k] == 0 {
+                    ready.insert(k);
+                }
+            }
+        }
+        ordered
+    }
+
+    /// Returns where the path will end up once the plan is executed, following
+    /// the renames of the directories containing it.
+    fn final_path(&self, path: &Path) -> PathBuf {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+                let parent = match self.actions.get(parent) {
+                    Some(Action::Rename { path }) => self.final_path(path),
+                    _ => self.final_path(parent),
+                };
+                parent.join(name)
+            }
+            _ => path.to_path_buf(),
+        }
     }
 
-    fn present_long(&self) {
-        println!(
+    /// Returns where the entry will end up once the plan is executed, whether
+    /// it is itself moved or renamed, or inside a directory that is.
+    fn final_location(&self, path: &Path) -> PathBuf {
+        match self.actions.get(path) {
+            Some(action @ (Action::Move { .. } | Action::Rename { .. })) => {
+                self.final_path(&action.destination(path))
+            }
+            _ => self.final_path(path),
+        }
+    }
+
+    /// Lists the tracked files each source the plan moves or renames is or
+    /// contains, by their absolute paths and their paths before the plan is
+    /// executed.
+    fn tracked_files(&self, index: &mut GitIndex) -> Result<TrackedFiles, Box<dyn Error>> {
+        let mut files = Vec::new();
+        for (src, action) in &self.actions {
+            if action.vacates() {
+                files.push((src.clone(), index.tracked_files(src)?));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Pairs the absolute paths of the tracked files with their final paths,
+    /// for the sources the plan moves or renames.
+    fn tracked_renames(&self, files: &TrackedFiles) -> Vec<(PathBuf, PathBuf)> {
+        let mut renames = BTreeMap::new();
+        for (src, files) in files {
+            if self.actions.contains_key(src) {
+                for (old, path) in files {
+                    renames.insert(old.clone(), self.final_location(path));
+                }
+            }
+        }
+        renames.into_iter().collect()
+    }
+
+    fn present_short(&self, pager: bool) {
+        let msl = self.max_src_len;
+        let mdl = self.max_dst_len;
+        let mut text = String::new();
+        for (src, action) in self.listed() {
+            let (_, description) = action.describe();
+            let path = self.final_path(action.path());
+            let (src, path) = highlight(src, &path, matches!(action, Action::Rename { .. }));
+            let _ = writeln!(
+                text,
+                "{} {description} {}",
+                console::pad_str(&src, msl, console::Alignment::Left, None),
+                console::pad_str(&path, mdl, console::Alignment::Left, None),
+            );
+        }
+        self.write_skipped(&mut text);
+        progress::page(&text, pager);
+    }
+
+    fn present_long(&self, pager: bool) {
+        let mut text = String::new();
+        let _ = writeln!(
+            text,
             "--------------------------------------------------------------------------------"
         );
-        println!("Result:");
-        for (src, action) in &self.actions {
+        let _ = writeln!(text, "Result:");
+        for (src, action) in self.listed() {
             match action {
-                Action::Move { date_source, path } => {
-                    println!("  move");
-                    println!("    * date source: {date_source:?}");
-                    println!("    - {}", src.display());
-                    println!("    > {}", path.display());
-                }
                 Action::Rename { path } => {
-                    println!("  rename");
-                    println!("    - {}", src.display());
-                    println!("    + {}", path.display());
+                    let _ = writeln!(text, "  rename");
+                    if case::is_case_only_rename(src, path) {
+                        let _ = writeln!(text, "    * case only");
+                    }
+                    let (src, path) = highlight(src, &self.final_path(path), true);
+                    let _ = writeln!(text, "    - {src}");
+                    let _ = writeln!(text, "    + {path}");
+                }
+                _ => {
+                    let (name, _) = action.describe();
+                    let _ = writeln!(text, "  {name}");
+                    let _ = writeln!(text, "    * date source: {:?}", action.date_source());
+                    let _ = writeln!(text, "    - {}", display_path(src));
+                    let path = self.final_path(action.path());
+                    let _ = writeln!(text, "    > {}", display_path(&path));
                 }
             }
         }
+        self.write_skipped(&mut text);
+        progress::page(&text, pager);
     }
 
-    fn execute(&self) -> Result<(), Box<dyn Error>> {
+    /// Lists the entries left unchanged, with the reason why.
+    fn write_skipped(&self, text: &mut String) {
+        if self.skipped.is_empty() {
+            return;
+        }
+        let _ = writeln!(text, "Skipped:");
+        for (src, reason) in &self.skipped {
+            let _ = writeln!(text, "    {}: {reason}", display_path(src));
+        }
+    }
+
+    /// Presents the number of actions of each kind, of files dated from each
+    /// source, and of entries ending up in each directory.
+    fn present_summary(&self, pager: bool) {
+        let mut actions: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut date_sources: BTreeMap<String, usize> = BTreeMap::new();
+        let mut destinations: BTreeMap<PathBuf, usize> = BTreeMap::new();
+        for (src, action) in &self.actions {
+            *actions.entry(action.describe().0).or_default() += 1;
+            if let Some(date_source) = action.date_source() {
+                *date_sources.entry(format!("{date_source:?}")).or_default() += 1;
+            }
+            let destination = self.final_location(src);
+            let dir = destination.parent().unwrap_or(Path::new("")).to_path_buf();
+            *destinations.entry(dir).or_default() += 1;
+        }
+        let mut text = String::new();
+        let _ = writeln!(text, "Summary of {} entries:", self.actions.len());
+        let _ = writeln!(text, "  actions:");
+        for (action, count) in actions {
+            let _ = writeln!(text, "    {count:>8} {action}");
+        }
+        if !date_sources.is_empty() {
+            let _ = writeln!(text, "  date sources:");
+            for (date_source, count) in date_sources {
+                let _ = writeln!(text, "    {count:>8} {date_source}");
+            }
+        }
+        let _ = writeln!(text, "  destination directories:");
+        for (dir, count) in destinations {
+            let _ = writeln!(text, "    {count:>8} {}", display_path(&dir));
+        }
+        if !self.skipped.is_empty() {
+            let _ = writeln!(text, "  skipped: {}", self.skipped.len());
+        }
+        progress::page(&text, pager);
+    }
+
+    /// Fails if two entries would end up with the same path, once the
+    /// directories containing them are renamed, or an entry would replace one
+    /// that is not itself moved or renamed away, which `ordered` then does
+    /// first. Paths in directories on case-insensitive filesystems are
+    /// compared ignoring case.
+    fn check_collisions(&self) -> Result<(), Box<dyn Error>> {
+        let mut probe = CaseProbe::new();
+        let sources: HashSet<String> = self
+            .actions
+            .iter()
+            .filter(|(_, action)| action.vacates())
+            .map(|(src, _)| probe.collision_key(src))
+            .collect();
+        let mut destinations: HashMap<String, &PathBuf> = HashMap::new();
+        let mut collisions = Vec::new();
         for (src, action) in &self.actions {
+            let dst = action.destination(src);
+            let last = self.final_path(&dst);
+            let key = probe.collision_key(&last);
+            if let Some(other) = destinations.get(&key) {
+                collisions.push(format!(
+                    "{} and {} would both become {}",
+                    other.display(),
+                    src.display(),
+                    last.display()
+                ));
+            } else if fs::symlink_metadata(&dst).is_ok()
+                && !sources.contains(&probe.collision_key(&dst))
+                && !probe.is_same_entry(src, &dst)
+            {
+                collisions.push(format!(
+                    "{} would replace the existing {}",
+                    src.display(),
+                    dst.display()
+                ));
+            }
+            destinations.entry(key).or_insert(src);
+        }
+        if collisions.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Collisions in plan: {}", collisions.join("; ")).into())
+        }
+    }
+
+    /// Fails if the name of an entry, or of a directory created, does not
+    /// follow the rules of the filesystem the entries are meant for.
+    fn check_portable(&self, portability: Portability) -> Result<(), Box<dyn Error>> {
+        let destinations = self
+            .actions
+            .iter()
+            .map(|(src, action)| action.destination(src));
+        let mut violations = Vec::new();
+        for path in destinations.chain(self.dirs.iter().cloned()) {
+            let name = path.file_name().unwrap_or_default();
+            let violation = match name.to_str() {
+                Some(name) => portability.violation(name),
+                None if portability.requires_unicode() => Some(String::from("is not valid UTF-8")),
+                None => None,
+            };
+            if let Some(violation) = violation {
+                violations.push(format!("{} {}", display_path(&path), violation));
+            }
+        }
+        violations.sort();
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Names not portable to {}: {}",
+                format!("{portability:?}").to_lowercase(),
+                violations.join("; ")
+            )
+            .into())
+        }
+    }
+
+    /// Executes the actions, then records the renames of tracked files in git.
+    /// If an action fails, the renames already done are recorded before
+    /// returning the error.
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let mut index = GitIndex::new();
+        let tracked = if self.use_git {
+            self.tracked_files(&mut index)?
+        } else {
+            Vec::new()
+        };
+        let mut executed = Plan::new();
+        let result = self.execute_actions(&mut executed);
+        let renames = executed.tracked_renames(&tracked);
+        if !renames.is_empty() {
+            index.record_renames(&renames)?;
+        }
+        result
+    }
+
+    /// Executes the actions in order, keeping those done in the other plan,
+    /// as actions on their original sources.
+    fn execute_actions(&self, executed: &mut Plan) -> Result<(), Box<dyn Error>> {
+        let mut bar = progress::bar(self.progress, self.actions.len() as u64, "Executing");
+        // Adding a hidden bar to the others would draw it.
+        if self.progress {
+            bar = self.transfer.bars().add(bar);
+        }
+        // The sources moved out of the way to a temporary name, by that name.
+        let mut temporary: HashMap<PathBuf, PathBuf> = HashMap::new();
+        for (src, action) in &self.ordered() {
+            bar.inc(1);
             match action {
                 Action::Move { path, .. } => {
                     create_directory(path)?;
-                    move_file(src, path)?;
+                    move_file(&self.transfer, src, path)?;
+                }
+                Action::Copy { path, .. } => {
+                    create_directory(path)?;
+                    self.transfer.copy(src, &action.destination(src))?;
+                }
+                Action::Hardlink { path, .. } => {
+                    create_directory(path)?;
+                    fs::hard_link(src, action.destination(src))?;
+                }
+                Action::Symlink { path, .. } => {
+                    create_directory(path)?;
+                    transfer::symlink(src, &action.destination(src))?;
+                }
+                Action::Reflink { path, .. } => {
+                    create_directory(path)?;
+                    transfer::reflink(src, &action.destination(src))?;
                 }
                 Action::Rename { path } => {
-                    fs_rename_file(self.use_git, src, path)?;
+                    if let Some(parent) = path.parent() {
+                        if !parent.as_os_str().is_empty() && !parent.exists() {
+                            fs::create_dir_all(parent)?;
+                        }
+                    }
+                    fs_rename_file(&self.transfer, src, path)?;
                 }
             };
+            let original = temporary.remove(src).unwrap_or_else(|| src.clone());
+            let destination = action.destination(src);
+            if self.actions[&original].destination(&original) != destination {
+                temporary.insert(destination, original.clone());
+            }
+            executed.actions.insert(original, action.clone());
         }
+        bar.finish_and_clear();
         Ok(())
     }
 
-    fn create_undo(&self) -> io::Result<()> {
-        let git = if self.use_git { "git " } else { "" };
+    fn create_undo(&self) -> Result<(), Box<dyn Error>> {
+        let mut index = GitIndex::new();
         let mut undo_file = std::fs::File::create("undo.sh")?;
-        for (src, action) in &self.actions {
+        // Undoing runs in the reverse order, restoring directories before the
+        // entries inside them.
+        for (src, action) in self.ordered().iter().rev() {
+            let git = if self.use_git && index.is_tracked(src)? {
+                "git "
+            } else {
+                ""
+            };
             match action {
                 Action::Move { path, .. } => {
                     let mut dst_path = PathBuf::new();
                     dst_path.push(path);
                     dst_path.push(src.file_name().unwrap());
-                    writeln!(
-                        undo_file,
-                        "{}mv \"{}\" \"{}\"",
-                        git,
-                        dst_path.display(),
-                        src.display()
-                    )?;
+                    write_command(&mut undo_file, &format!("{git}mv"), &[&dst_path, src])?;
                 }
                 Action::Rename { path } => {
-                    writeln!(
-                        undo_file,
-                        "{}mv \"{}\" \"{}\"",
-                        git,
-                        path.display(),
-                        src.display()
-                    )?;
+                    write_command(&mut undo_file, &format!("{git}mv"), &[path, src])?;
+                }
+                // Undoing a copy or a link deletes it, leaving the original.
+                _ => {
+                    write_command(&mut undo_file, "rm", &[&action.destination(src)])?;
                 }
             };
         }
-        for dir in &self.dirs {
-            writeln!(undo_file, "rmdir {}", dir.display())?;
+        // Nested directories must be removed before their parents.
+        let mut dirs: Vec<&PathBuf> = self.dirs.iter().collect();
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        for dir in dirs {
+            write_command(&mut undo_file, "rmdir", &[dir])?;
         }
         Ok(())
     }
 }
 
-fn path_length(path: &Path) -> usize {
-    path.as_os_str()
-        .to_str()
-        .expect("Unable to convert file path into string.")
-        .chars()
-        .count()
+/// The tracked files each source moved or renamed is or contains, by their
+/// absolute paths and their paths before the plan is executed.
+type TrackedFiles = Vec<(PathBuf, Vec<(PathBuf, PathBuf)>)>;
+
+/// Writes a command of the undo script, with its arguments quoted byte for
+/// byte, so that paths which are not valid UTF-8 are restored as they were.
+fn write_command(file: &mut fs::File, command: &str, paths: &[&Path]) -> io::Result<()> {
+    let mut line = command.as_bytes().to_vec();
+    for path in paths {
+        line.extend_from_slice(b" \"");
+        line.extend_from_slice(path.as_os_str().as_encoded_bytes());
+        line.push(b'"');
+    }
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+/// Returns the path as it is shown to the user, with the bytes that are not
+/// valid UTF-8 shown as `%XX`.
+fn display_path(path: &Path) -> Cow<'_, str> {
+    if let Some(path) = path.to_str() {
+        return Cow::Borrowed(path);
+    }
+    let mut text = String::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            let _ = write!(text, "%{byte:02X}");
+        }
+    }
+    Cow::Owned(text)
+}
+
+/// Returns the number of columns the path takes up on the terminal.
+fn path_width(path: &Path) -> usize {
+    console::measure_text_width(&display_path(path))
+}
+
+/// Renders the old and new paths, with the changes between them highlighted
+/// if they are comparable, that is, if the old path is renamed to the new one.
+fn highlight(src: &Path, dst: &Path, comparable: bool) -> (String, String) {
+    let (src, dst) = (display_path(src), display_path(dst));
+    if !comparable {
+        return (src.into_owned(), dst.into_owned());
+    }
+    let changes = diff::changes(&src, &dst);
+    (diff::before(&changes), diff::after(&changes))
+}
+
+/// Runs the closure on a pool of the given number of worker threads, or of one
+/// per CPU if zero, so that parallel iterators inside it use that pool.
+fn with_jobs<T: Send>(jobs: usize, f: impl FnOnce() -> T + Send) -> Result<T, Box<dyn Error>> {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    Ok(pool.install(f))
 }
 
 /// Asks the user for confirmation before proceeding.
@@ -314,23 +964,36 @@ fn user_confirm() -> bool {
         .unwrap_or(false)
 }
 
-fn user_input() -> String {
-    Input::new().with_prompt(">").interact_text().unwrap()
+/// Asks the user whether to execute an action, which is skipped along with
+/// all the remaining ones if the answer cannot be read.
+fn user_answer() -> Answer {
+    Input::<String>::new()
+        .with_prompt("Execute? [y,n,a,q,e]")
+        .validate_with(|input: &String| match Answer::parse(input) {
+            Some(_) => Ok(()),
+            None => Err("Answer y(es), n(o), a(ll), q(uit) or e(dit)."),
+        })
+        .interact_text()
+        .ok()
+        .and_then(|input| Answer::parse(&input))
+        .unwrap_or(Answer::Quit)
 }
 
-/// Returns true if the directory entry begins with a period.
-fn is_hidden(entry: &Path) -> bool {
-    entry
-        .file_name()
-        .and_then(|s| s.to_str())
-        .is_some_and(|s| (s != "." || s != "./") && s.starts_with('.'))
+/// Asks the user for a new destination, starting from the current one.
+fn user_edit(path: &Path) -> PathBuf {
+    Input::<String>::new()
+        .with_prompt("Destination")
+        .with_initial_text(path.to_string_lossy())
+        .interact_text()
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| path.to_path_buf())
 }
 
-/// Given a path, creates a directory.
+/// Given a path, creates a directory and any missing parents.
 fn create_directory(directory: &Path) -> io::Result<()> {
     let mut full_path = PathBuf::new();
     full_path.push(directory);
-    match std::fs::create_dir(&full_path) {
+    match std::fs::create_dir_all(&full_path) {
         Ok(_) => Ok(()),
         Err(reason) => match reason.kind() {
             io::ErrorKind::AlreadyExists => Ok(()),
@@ -340,34 +1003,269 @@ fn create_directory(directory: &Path) -> io::Result<()> {
 }
 
 /// Given source and destination paths, will move the source to the destination.
-fn move_file(src: &PathBuf, dir: &PathBuf) -> io::Result<()> {
+fn move_file(transfer: &Transfer, src: &Path, dir: &Path) -> io::Result<()> {
     let mut dst = PathBuf::new();
     dst.push(dir);
     dst.push(src.file_name().unwrap());
-    std::fs::rename(src, dst)?;
+    transfer.rename(src, &dst)?;
     Ok(())
 }
 
-fn rename_file(path: &mut PathBuf, filename: String) {
-    let extension = match path.extension() {
-        None => String::new(),
-        Some(extension) => String::from(extension.to_str().unwrap()),
-    };
-    path.set_file_name(filename);
-    path.set_extension(extension);
+fn rename_file(path: &mut PathBuf, filename: String, splitter: &ExtensionSplitter) {
+    splitter.set_stem(path, &filename);
 }
 
-fn fs_rename_file(use_git: bool, src: &PathBuf, dst: &PathBuf) -> io::Result<()> {
-    if use_git {
-        let src = src.to_str().unwrap();
-        let dst = dst.to_str().unwrap();
-        let _output = Command::new("git")
-            .args(["mv", src, dst])
-            .output()
-            .expect("Error invoking git.");
-        // TODO: do something with the output
+/// Renames an entry. A case-only rename goes through a temporary name, since
+/// on case-insensitive filesystems the destination is the source itself.
+fn fs_rename_file(transfer: &Transfer, src: &Path, dst: &Path) -> io::Result<()> {
+    if case::is_case_only_rename(src, dst) {
+        let temporary = case::temporary_path(src);
+        transfer.rename(src, &temporary)?;
+        transfer.rename(&temporary, dst)
     } else {
-        fs::rename(src, dst)?
+        transfer.rename(src, dst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rename_plan(renames: &[(&str, &str)]) -> Plan {
+        let mut plan = Plan::new();
+        for (src, dst) in renames {
+            let path = PathBuf::from(dst);
+            plan.insert(PathBuf::from(src), Action::Rename { path });
+        }
+        plan
+    }
+
+    #[test]
+    fn path_width_test() {
+        assert_eq!(9, path_width(Path::new("Photo.JPG")));
+        assert_eq!(10, path_width(Path::new("Fotoño.jpg")));
+        assert_eq!(8, path_width(Path::new("写真.jpg")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn path_width_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"Photo\xff.jpg"));
+        assert_eq!(12, path_width(path));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn display_path_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(OsStr::from_bytes(b"dir/caf\xe9 \xff.jpg"));
+        assert_eq!("dir/caf%E9 %FF.jpg", display_path(path));
+        assert_eq!("dir/café.jpg", display_path(Path::new("dir/café.jpg")));
+    }
+
+    #[test]
+    fn parse_answer() {
+        assert_eq!(Some(Answer::Yes), Answer::parse("y"));
+        assert_eq!(Some(Answer::No), Answer::parse(" N "));
+        assert_eq!(Some(Answer::All), Answer::parse("all"));
+        assert_eq!(Some(Answer::Quit), Answer::parse("q"));
+        assert_eq!(Some(Answer::Edit), Answer::parse("e"));
+        assert_eq!(None, Answer::parse("x"));
+        assert_eq!(None, Answer::parse(""));
+    }
+
+    #[test]
+    fn retain_recomputes_dirs() {
+        let mut plan = Plan::new();
+        for (src, dir) in [("a.jpg", "2024-5-1"), ("b.jpg", "2024-5-2")] {
+            let action = Action::Move {
+                date_source: None,
+                path: PathBuf::from(dir),
+            };
+            plan.insert(PathBuf::from(src), action);
+        }
+        plan.retain(|src| src == Path::new("b.jpg"));
+        assert_eq!(
+            vec![Path::new("b.jpg")],
+            plan.actions.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(HashSet::from([PathBuf::from("2024-5-2")]), plan.dirs);
+    }
+
+    #[test]
+    fn ordered_children_first() {
+        let plan = rename_plan(&[
+            ("root/A", "root/a"),
+            ("root/A/B", "root/A/b"),
+            ("root/A/B/C.txt", "root/A/B/c.txt"),
+            ("root/D.txt", "root/d.txt"),
+        ]);
+        let order: Vec<PathBuf> = plan.ordered().into_iter().map(|(src, _)| src).collect();
+        assert_eq!(
+            vec![
+                PathBuf::from("root/A/B/C.txt"),
+                PathBuf::from("root/A/B"),
+                PathBuf::from("root/A"),
+                PathBuf::from("root/D.txt"),
+            ],
+            order
+        );
+    }
+
+    #[test]
+    fn ordered_vacates_destinations_first() {
+        let plan = rename_plan(&[("a.txt", "aa.txt"), ("aa.txt", "aaaa.txt")]);
+        let order: Vec<PathBuf> = plan.ordered().into_iter().map(|(src, _)| src).collect();
+        assert_eq!(vec![PathBuf::from("aa.txt"), PathBuf::from("a.txt")], order);
+    }
+
+    /// Executes renames in a new directory holding files named after their
+    /// contents, returning the names and contents left.
+    fn execute_renames(name: &str, renames: &[(&str, &str)]) -> BTreeMap<String, String> {
+        let dir = std::env::temp_dir().join(format!("ocd-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut plan = Plan::new();
+        for (src, dst) in renames {
+            fs::write(dir.join(src), src).unwrap();
+            let path = dir.join(dst);
+            plan.insert(dir.join(src), Action::Rename { path });
+        }
+        plan.check_collisions().unwrap();
+        plan.execute().unwrap();
+        let files = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, fs::read_to_string(&path).unwrap())
+            })
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        files
+    }
+
+    #[test]
+    fn execute_chain() {
+        let files = execute_renames("chain", &[("a", "aa"), ("aa", "aaaa")]);
+        let expected = BTreeMap::from([
+            (String::from("aa"), String::from("a")),
+            (String::from("aaaa"), String::from("aa")),
+        ]);
+        assert_eq!(expected, files);
+    }
+
+    #[test]
+    fn execute_swap() {
+        let files = execute_renames("swap", &[("a", "b"), ("b", "c"), ("c", "a")]);
+        let expected = BTreeMap::from([
+            (String::from("a"), String::from("c")),
+            (String::from("b"), String::from("a")),
+            (String::from("c"), String::from("b")),
+        ]);
+        assert_eq!(expected, files);
+        let files = execute_renames("pair", &[("x", "y"), ("y", "x")]);
+        let expected = BTreeMap::from([
+            (String::from("x"), String::from("y")),
+            (String::from("y"), String::from("x")),
+        ]);
+        assert_eq!(expected, files);
+    }
+
+    #[test]
+    fn execute_records_renames_done_before_failing() {
+        let dir = crate::ocd::git::test::init_repository("partial", &["a.txt", "c.txt"]);
+        let mut plan = rename_plan(&[]).with_git(true);
+        for (src, dst) in [("a.txt", "x.txt"), ("b.txt", "y.txt"), ("c.txt", "z.txt")] {
+            let path = dir.join(dst);
+            plan.insert(dir.join(src), Action::Rename { path });
+        }
+        // b.txt does not exist, so renaming it fails after a.txt is renamed.
+        assert!(plan.execute().is_err());
+        assert_eq!(vec!["c.txt", "x.txt"], crate::ocd::git::test::staged(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn listed_in_sort_order() {
+        let mut plan = rename_plan(&[
+            ("IMG_10.jpg", "Trip_3.jpg"),
+            ("IMG_2.jpg", "Trip_2.jpg"),
+            ("IMG_1.jpg", "Trip_1.jpg"),
+        ]);
+        let listed = |plan: &Plan| -> Vec<PathBuf> {
+            plan.listed()
+                .into_iter()
+                .map(|(src, _)| src.clone())
+                .collect()
+        };
+        assert_eq!(
+            vec![
+                PathBuf::from("IMG_1.jpg"),
+                PathBuf::from("IMG_10.jpg"),
+                PathBuf::from("IMG_2.jpg"),
+            ],
+            listed(&plan)
+        );
+        plan.sort_by(Order::Natural, 1).unwrap();
+        assert_eq!(
+            vec![
+                PathBuf::from("IMG_1.jpg"),
+                PathBuf::from("IMG_2.jpg"),
+                PathBuf::from("IMG_10.jpg"),
+            ],
+            listed(&plan)
+        );
+    }
+
+    #[test]
+    fn case_only_rename_onto_other_file_collides() {
+        let dir = std::env::temp_dir().join(format!("ocd-case-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Photo.jpg"), b"UPPER").unwrap();
+        let plan = rename_plan(&[(
+            dir.join("Photo.jpg").to_str().unwrap(),
+            dir.join("photo.jpg").to_str().unwrap(),
+        )]);
+        // On a case-insensitive filesystem, the destination is the source.
+        let insensitive = fs::symlink_metadata(dir.join("photo.jpg")).is_ok();
+        assert!(plan.check_collisions().is_ok());
+        fs::write(dir.join("photo.jpg"), b"lower").unwrap();
+        assert_eq!(insensitive, plan.check_collisions().is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn collisions_after_parents_renamed() {
+        let plan = rename_plan(&[
+            ("root/A", "root/B"),
+            ("root/A/x.txt", "root/A/y.txt"),
+            ("root/C/y.txt", "root/B/y.txt"),
+        ]);
+        let error = plan.check_collisions().unwrap_err().to_string();
+        assert!(error.contains("would both become root/B/y.txt"));
+    }
+
+    #[test]
+    fn final_path_follows_parents() {
+        let plan = rename_plan(&[
+            ("root/A", "root/a"),
+            ("root/A/B", "root/A/b"),
+            ("root/A/B/C.txt", "root/A/B/c.txt"),
+        ]);
+        assert_eq!(
+            PathBuf::from("root/a/b/c.txt"),
+            plan.final_path(Path::new("root/A/B/c.txt"))
+        );
+        assert_eq!(
+            PathBuf::from("root/a/b"),
+            plan.final_path(Path::new("root/A/b"))
+        );
+        assert_eq!(
+            PathBuf::from("root/E/f.txt"),
+            plan.final_path(Path::new("root/E/f.txt"))
+        );
     }
-    Ok(())
 }
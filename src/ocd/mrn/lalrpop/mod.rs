@@ -10,11 +10,13 @@ pub mod mrn_parser {
 mod test {
     use super::*;
     use crate::ocd;
+    use crate::ocd::mrn::program::Condition;
     use crate::ocd::mrn::program::Instruction;
     use crate::ocd::mrn::program::ReplaceArg;
     use crate::ocd::mrn::program::ReplacePattern;
     use crate::ocd::mrn::program::ReplacePatternComponent;
     use crate::ocd::mrn::Position;
+    use regex::Regex;
 
     fn parse_input(input: &str) -> Vec<Instruction> {
         let lexer = ocd::mrn::lalrpop::mrn_lexer::Lexer::new(input);
@@ -193,6 +195,108 @@ mod test {
         assert_eq!(expected.as_slice(), result.as_slice());
     }
 
+    #[test]
+    fn parse_extension_chain() {
+        let input = "erc, eac 'tar.gz'";
+        let expected: Vec<Instruction> = vec![
+            Instruction::ExtensionChainRemove,
+            Instruction::ExtensionChainAdd(String::from("tar.gz")),
+        ];
+        let result = parse_input(input);
+        assert_eq!(expected.as_slice(), result.as_slice());
+    }
+
+    #[test]
+    fn parse_transcode() {
+        let input = "tc 'cp1252', cl";
+        let expected: Vec<Instruction> = vec![
+            Instruction::Transcode(String::from("cp1252")),
+            Instruction::CaseLower,
+        ];
+        let result = parse_input(input);
+        assert_eq!(expected.as_slice(), result.as_slice());
+    }
+
+    #[test]
+    fn parse_reorder() {
+        let input = "o, o '3 1 2', o '-' '2 1'";
+        let expected: Vec<Instruction> = vec![
+            Instruction::Reorder,
+            Instruction::ReorderFields {
+                separator: String::from(" "),
+                order: vec![3, 1, 2],
+            },
+            Instruction::ReorderFields {
+                separator: String::from("-"),
+                order: vec![2, 1],
+            },
+        ];
+        let result = parse_input(input);
+        assert_eq!(expected.as_slice(), result.as_slice());
+    }
+
+    #[test]
+    fn parse_joins() {
+        let input = "jcl,jsu,jt,jd";
+        let expected: Vec<Instruction> = vec![
+            Instruction::JoinLowerCamel,
+            Instruction::JoinScreamingSnake,
+            Instruction::JoinTrain,
+            Instruction::JoinDot,
+        ];
+        let result = parse_input(input);
+        assert_eq!(expected.as_slice(), result.as_slice());
+    }
+
+    #[test]
+    fn parse_unicode() {
+        let input = "su,nfc,nfd,sd,tl";
+        let expected: Vec<Instruction> = vec![
+            Instruction::SanitizeUnicode,
+            Instruction::NormalizeComposed,
+            Instruction::NormalizeDecomposed,
+            Instruction::StripDiacritics,
+            Instruction::Transliterate,
+        ];
+        let result = parse_input(input);
+        assert_eq!(expected.as_slice(), result.as_slice());
+    }
+
+    #[test]
+    fn parse_if() {
+        let input = "cl, if match '^img_' { rsu, ea 'jpg' }, cu";
+        let expected: Vec<Instruction> = vec![
+            Instruction::CaseLower,
+            Instruction::If {
+                condition: Condition::Match(Regex::new("^img_").unwrap()),
+                instructions: vec![
+                    Instruction::Replace {
+                        pattern: ReplaceArg::Space,
+                        replace: ReplaceArg::Underscore,
+                    },
+                    Instruction::ExtensionAdd(String::from("jpg")),
+                ],
+            },
+            Instruction::CaseUpper,
+        ];
+        let result = parse_input(input);
+        assert_eq!(expected.as_slice(), result.as_slice());
+    }
+
+    #[test]
+    fn parse_unless_nested() {
+        let input = "unless ext 'mp3' { if match 'x' { s } }";
+        let expected: Vec<Instruction> = vec![Instruction::Unless {
+            condition: Condition::Extension(String::from("mp3")),
+            instructions: vec![Instruction::If {
+                condition: Condition::Match(Regex::new("x").unwrap()),
+                instructions: vec![Instruction::Sanitize],
+            }],
+        }];
+        let result = parse_input(input);
+        assert_eq!(expected.as_slice(), result.as_slice());
+    }
+
     #[test]
     fn parse_pattern_match() {
         let input = "p '{N} - {X}' '{1} {sng10+2,2} {2}'";
@@ -205,7 +309,8 @@ mod test {
                     ReplacePatternComponent::SequentialNumberGenerator {
                         start: 10,
                         step: 2,
-                        padding: 2,
+                        padding: Some(2),
+                        order: None,
                     },
                     ReplacePatternComponent::Literal(String::from(" ")),
                     ReplacePatternComponent::Florb(2),
@@ -7,13 +7,27 @@ use std::num::ParseIntError;
 pub enum LexicalError {
     InvalidInteger(ParseIntError),
     InvalidReplacePattern,
+    InvalidFieldOrder(String),
+    InvalidSequenceOrder(String),
+    InvalidRegex(String),
     #[default]
     InvalidToken,
 }
 
 impl fmt::Display for LexicalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            LexicalError::InvalidFieldOrder(order) => write!(
+                f,
+                "Invalid field order '{order}', expected field numbers separated by spaces."
+            ),
+            LexicalError::InvalidSequenceOrder(order) => write!(
+                f,
+                "Invalid sequence order '{order}', expected name, natural, mtime, ctime, exif or size."
+            ),
+            LexicalError::InvalidRegex(err) => write!(f, "Invalid regular expression: {err}"),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
@@ -36,6 +50,16 @@ pub enum Token {
     Comma,
     #[token("s")]
     Sanitize,
+    #[token("su")]
+    SanitizeUnicode,
+    #[token("nfc")]
+    NormalizeComposed,
+    #[token("nfd")]
+    NormalizeDecomposed,
+    #[token("sd")]
+    StripDiacritics,
+    #[token("tl")]
+    Transliterate,
     #[token("cl")]
     CaseLower,
     #[token("cu")]
@@ -50,6 +74,14 @@ pub enum Token {
     JoinSnake,
     #[token("jk")]
     JoinKebab,
+    #[token("jcl")]
+    JoinLowerCamel,
+    #[token("jsu")]
+    JoinScreamingSnake,
+    #[token("jt")]
+    JoinTrain,
+    #[token("jd")]
+    JoinDot,
     #[token("sc")]
     SplitCamel,
     #[token("ss")]
@@ -92,10 +124,30 @@ pub enum Token {
     ExtensionAdd,
     #[token("er")]
     ExtensionRemove,
+    #[token("eac")]
+    ExtensionChainAdd,
+    #[token("erc")]
+    ExtensionChainRemove,
+    #[token("tc")]
+    Transcode,
     #[token("o")]
     Reorder,
     #[token("p")]
     PatternMatch,
+    #[token("{")]
+    OpeningBrace,
+    #[token("}")]
+    ClosingBrace,
+    #[token("if")]
+    If,
+    #[token("unless")]
+    Unless,
+    #[token("match")]
+    Match,
+    #[token("ext")]
+    Extension,
+    #[token("call")]
+    Call,
 }
 
 impl fmt::Display for Token {
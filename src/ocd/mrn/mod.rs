@@ -3,31 +3,56 @@
 //! This command implements a small interpreter with a number of shortcuts to
 //! common filename manipulation actions.
 
+use crate::ocd::config::is_unset;
+use crate::ocd::config::MassRenameConfig;
+use crate::ocd::diff;
+use crate::ocd::extension::ExtensionMode;
+use crate::ocd::extension::ExtensionSplitter;
+use crate::ocd::mrn::program::parse_field_order;
+use crate::ocd::mrn::program::Condition;
 use crate::ocd::mrn::program::Instruction;
 use crate::ocd::mrn::program::Position;
 use crate::ocd::mrn::program::Program;
 use crate::ocd::mrn::program::ReplaceArg;
+use crate::ocd::mrn::title_case::TitleCase;
+use crate::ocd::order::Order;
+use crate::ocd::portable::Portability;
+use crate::ocd::progress;
+use crate::ocd::review;
+use crate::ocd::review::Replan;
+use crate::ocd::walk::WalkArgs;
+use crate::ocd::walk::Walker;
 use crate::ocd::Action;
+use crate::ocd::Confirmation;
 use crate::ocd::Mode;
 use crate::ocd::Plan;
 use crate::ocd::Speaker;
 use crate::ocd::Verbosity;
+use clap::ArgMatches;
 use clap::Args;
-use heck::ToKebabCase;
-use heck::ToSnakeCase;
-use heck::ToTitleCase;
-use heck::ToUpperCamelCase;
+use clap::ColorChoice;
+use clap::ValueEnum;
+use dialoguer::Input;
+use encoding_rs::Encoding;
+use rayon::prelude::*;
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::fs;
+use std::ffi::OsStr;
+use std::path::Component;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::sync::LazyLock;
-use walkdir::WalkDir;
+use std::sync::OnceLock;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
 
 mod lalrpop;
 mod pattern_match;
 mod program;
+mod rules;
+mod title_case;
 
 /// Arguments to the Mass Re-Name
 #[derive(Clone, Debug, Args)]
@@ -56,16 +81,49 @@ Default is low, one medium, two high, three or more debug."#)]
     #[arg(help = "Create undo script.")]
     #[arg(long)]
     #[arg(short = 'u')]
+    #[arg(overrides_with = "no_undo")]
     undo: bool,
 
+    #[arg(help = "Do not create an undo script, even if the configuration file says so.")]
+    #[arg(long)]
+    #[arg(overrides_with = "undo")]
+    no_undo: bool,
+
     #[arg(help = "Do not ask for confirmation.")]
     #[arg(long)]
     yes: bool,
 
-    #[arg(help = "Rename files by calling `git mv`")]
+    #[arg(help = r#"Review the plan in a terminal interface before executing it,
+accepting, rejecting or editing each entry, and editing the rules with the
+plan updated as they are typed. Only the accepted entries are renamed."#)]
+    #[arg(long)]
+    #[arg(conflicts_with = "yes")]
+    review: bool,
+
+    #[arg(default_value = "all")]
+    #[arg(
+        help = r#"Specifies whether the whole plan is confirmed at once, or each
+entry in turn, answering y(es), n(o), a(ll remaining), q(uit, skipping the
+remaining ones) or e(dit the destination)."#
+    )]
+    #[arg(long)]
+    #[arg(value_name = "WHEN")]
+    #[arg(conflicts_with_all = ["yes", "review"])]
+    confirm: Confirmation,
+
+    #[arg(
+        help = r#"Record the renames of tracked files in git, as `git mv` does.
+Untracked files are simply renamed."#
+    )]
     #[arg(long)]
+    #[arg(overrides_with = "no_git")]
     git: bool,
 
+    #[arg(help = "Do not record renames in git, even if the configuration file says so.")]
+    #[arg(long)]
+    #[arg(overrides_with = "git")]
+    no_git: bool,
+
     #[arg(default_value = "files")]
     #[arg(help = "Specified whether the rules are applied to directories, files or all.")]
     #[arg(short = 'm')]
@@ -75,18 +133,146 @@ Default is low, one medium, two high, three or more debug."#)]
     #[arg(help = "Recurse directories.")]
     #[arg(long)]
     #[arg(short = 'r')]
+    #[arg(overrides_with = "no_recurse")]
     recurse: bool,
 
+    #[arg(help = "Do not recurse directories, even if the configuration file says so.")]
+    #[arg(long)]
+    #[arg(overrides_with = "recurse")]
+    no_recurse: bool,
+
+    #[arg(default_value = "stem")]
+    #[arg(help = r#"Specifies the part of each path the rules are applied to:
+the file name without its extension, the whole file name, the extension,
+or the whole path relative to --dir. Directories missing from a new path
+are created."#)]
+    #[arg(long)]
+    scope: Scope,
+
+    #[arg(default_value = "compound")]
+    #[arg(
+        help = r#"Specifies which dot-separated components of a file name make up
+its extension: only the last one, the longest chain matching a compound
+extension such as `tar.gz` or `en.srt`, or all but the first one."#
+    )]
+    #[arg(long)]
+    extensions: ExtensionMode,
+
+    #[arg(help = r#"Adds a compound extension, which may be a glob pattern,
+e.g. `min.js` or `[a-z][a-z].srt`. May be given multiple times."#)]
+    #[arg(long = "compound-ext")]
+    #[arg(value_name = "EXT")]
+    compound_ext: Vec<String>,
+
+    #[arg(
+        help = r#"Rewrite the new names to follow the rules of the filesystem they
+are meant for, replacing forbidden characters, suffixing reserved names such as
+`CON`, removing trailing periods and spaces, and shortening long names while
+keeping their extension. The plan fails if a name still breaks these rules."#
+    )]
+    #[arg(long)]
+    #[arg(value_name = "FILESYSTEM")]
+    #[arg(overrides_with = "no_portable")]
+    portable: Option<Portability>,
+
+    #[arg(
+        help = "Keep the new names as they are, even if the configuration file sets a filesystem."
+    )]
+    #[arg(long = "no-portable")]
+    #[arg(overrides_with = "portable")]
+    no_portable: bool,
+
+    #[arg(
+        help = r#"The comma-separated words kept lowercase by `ct` unless they start
+or end the name or a phrase in it, replacing the default ones,
+e.g. `--minor-words a,of,the,y,de,la`."#
+    )]
+    #[arg(long = "minor-words")]
+    #[arg(value_name = "WORDS")]
+    #[arg(value_delimiter = ',')]
+    minor_words: Option<Vec<String>>,
+
+    #[arg(
+        help = r#"The order entries are listed in, and numbered in by `{sng}` within
+each directory unless it gives another. By default entries are listed by name
+and numbered in natural order."#
+    )]
+    #[arg(long = "sort-by")]
+    #[arg(value_name = "ORDER")]
+    #[arg(overrides_with = "no_sort_by")]
+    sort_by: Option<Order>,
+
+    #[arg(
+        help = r#"List entries by name and number them in natural order, even if
+the configuration file sets an order."#
+    )]
+    #[arg(long = "no-sort-by")]
+    #[arg(overrides_with = "sort_by")]
+    no_sort_by: bool,
+
+    #[arg(
+        help = r#"Ask for the order of the fields of `o` once, for the first name,
+and apply the same order to every name."#
+    )]
+    #[arg(long = "reorder-once")]
+    reorder_once: bool,
+
+    #[arg(help = r#"Apply the rules in the `.ocdrc` file governing each entry,
+that is, the nearest one in the entry's directory or its ancestors.
+Entries not governed by a rule file are left untouched."#)]
+    #[arg(long)]
+    #[arg(conflicts_with_all = ["input", "glob"])]
+    auto: bool,
+
+    #[arg(help = r#"Show the number of entries for each action and destination
+directory instead of listing every entry."#)]
+    #[arg(long)]
+    summary: bool,
+
+    #[arg(help = "Print long listings directly instead of through the pager.")]
+    #[arg(long = "no-pager")]
+    no_pager: bool,
+
+    #[arg(default_value = "auto")]
+    #[arg(
+        help = r#"Specifies whether the changes to each name are highlighted in
+listings: if standard output is a terminal, always, or never."#
+    )]
+    #[arg(long)]
+    #[arg(value_name = "WHEN")]
+    color: ColorChoice,
+
+    #[arg(default_value = "0")]
+    #[arg(help = r#"Number of threads hashing files for `{sha}` and reading the
+dates and sizes entries are sorted by while planning, or 0 for one per CPU.
+Renaming is always done one entry at a time."#)]
+    #[arg(long)]
+    #[arg(short = 'j')]
+    #[arg(value_name = "N")]
+    jobs: usize,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+
     #[arg(help = r#"The rewrite rules to apply to filenames.
 The value is a comma-separated list of the following rules:
 s                    Sanitize
+su                   Sanitize, keeping letters and digits from any script
+nfc                  Normalize to composed characters (NFC), e.g. as used on Linux
+nfd                  Normalize to decomposed characters (NFD), e.g. as used on macOS
+sd                   Strip diacritics, e.g. `Bartók` becomes `Bartok`
+tl                   Transliterate to ASCII, e.g. `Москва` becomes `Moskva`
 cl                   Lower case
 cu                   Upper case
 ct                   Title case
 cs                   Sentence case
-jc                   Join camel case
-jk                   Join kebab case
-js                   Join snaje case
+jc                   Join camel case, e.g. `CamelCase`
+jcl                  Join lower camel case, e.g. `camelCase`
+jk                   Join kebab case, e.g. `kebab-case`
+js                   Join snake case, e.g. `snake_case`
+jsu                  Join screaming snake case, e.g. `SCREAMING_SNAKE`
+jt                   Join train case, e.g. `Train-Case`
+jd                   Join dot case, e.g. `dot.case`
 sc                   Split camel case
 sk                   Split kebab case
 ss                   Split snake case
@@ -112,16 +298,128 @@ d <index> <pos>      Delete from <index> to <position>
                      <pos> may be a non-negative integer or the keyword 'end'
 ea <extension>       Change the extension, or add it if the file has none.
 er                   Remove the extension.
+eac <extension>      Change the whole chain of extensions, e.g. `.tar.gz`, or add it.
+erc                  Remove the whole chain of extensions.
+tc <encoding>        Transcode a file name that is not valid UTF-8 from a legacy encoding,
+                     e.g. `tc 'cp1252'`, `tc 'latin1'` or `tc 'shift_jis'`.
 o                    Interactive reorder, see documentation on use.
-p <match> <replace>  Pattern match, see documentation on use."#)]
-    input: String,
+o <order>            Reorder the space-separated fields of the name, e.g. `o '3 1 2'`.
+o <sep> <order>      Reorder the fields separated by <sep>, e.g. `o '-' '2 1'`.
+                     Fields may be left out or repeated.
+                     Names with fewer fields than <order> refers to are skipped.
+p <match> <replace>  Pattern match, see documentation on use.
+call <name>          Run the program named <name> in the configuration file.
+                     <name> is a single-quote delimited string.
+                     An input of `@<name>` is the same as `call '<name>'`.
+if <cond> { <rules> }
+                     Apply the comma-separated <rules> only if <cond> holds.
+unless <cond> { <rules> }
+                     Apply the comma-separated <rules> only if <cond> does not hold.
+                     <cond> may be `match '<regex>'`, matched against the file name,
+                     or `ext '<extension>'`, compared ignoring case."#)]
+    #[arg(required_unless_present = "auto")]
+    input: Option<String>,
 
     #[arg(
         help = r#"Operate only on files matching the glob pattern, e.g. `-g \"*.mp3\"`.
-If --dir is specified as well it will be concatenated with the glob pattern.
-If --recurse is also specified it will be ignored."#
+The pattern is matched against paths relative to --dir,
+use `**/` to match at any depth."#
     )]
     glob: Option<String>,
+
+    /// Named programs from the configuration file.
+    #[arg(skip)]
+    programs: BTreeMap<String, String>,
+
+    /// Splits file names into stems and extensions.
+    #[arg(skip)]
+    splitter: ExtensionSplitter,
+
+    /// Applies title and sentence case.
+    #[arg(skip)]
+    title_case: TitleCase,
+
+    /// The order of the fields given for the first name, with --reorder-once.
+    #[arg(skip)]
+    reorder: Arc<OnceLock<Vec<usize>>>,
+}
+
+/// The part of a path the rewrite rules operate on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Scope {
+    Stem,
+    Name,
+    Ext,
+    Path,
+}
+
+impl MassRenameArgs {
+    /// Fills in the arguments not given on the command line with the user's
+    /// configured defaults, makes the named programs available, and sets up
+    /// the extension splitter and title casing.
+    pub(crate) fn with_config(
+        mut self,
+        config: &MassRenameConfig,
+        matches: &ArgMatches,
+    ) -> Result<Self, Box<dyn Error>> {
+        if let Some(verbosity) = config.verbosity.filter(|_| is_unset(matches, "verbosity")) {
+            self.verbosity = verbosity;
+        }
+        if let Some(mode) = config.mode.filter(|_| is_unset(matches, "mode")) {
+            self.mode = mode;
+        }
+        if let Some(git) = config
+            .git
+            .filter(|_| is_unset(matches, "git") && !self.no_git)
+        {
+            self.git = git;
+        }
+        if let Some(undo) = config
+            .undo
+            .filter(|_| is_unset(matches, "undo") && !self.no_undo)
+        {
+            self.undo = undo;
+        }
+        if let Some(recurse) = config
+            .recurse
+            .filter(|_| is_unset(matches, "recurse") && !self.no_recurse)
+        {
+            self.recurse = recurse;
+        }
+        self.programs = config.programs.clone();
+        if let Some(jobs) = config.jobs.filter(|_| is_unset(matches, "jobs")) {
+            self.jobs = jobs;
+        }
+        if let Some(confirm) = config.confirm.filter(|_| is_unset(matches, "confirm")) {
+            self.confirm = confirm;
+        }
+        if self.portable.is_none() && !self.no_portable {
+            self.portable = config.portable;
+        }
+        if let Some(extensions) = config
+            .extensions
+            .filter(|_| is_unset(matches, "extensions"))
+        {
+            self.extensions = extensions;
+        }
+        if self.sort_by.is_none() && !self.no_sort_by {
+            self.sort_by = config.sort_by;
+        }
+        if let Some(minor_words) = self.minor_words.as_ref().or(config.minor_words.as_ref()) {
+            self.title_case = TitleCase::new(minor_words);
+        }
+        self.splitter = ExtensionSplitter::new(self.extensions)
+            .with_compound(&config.compound_extensions)?
+            .with_compound(&self.compound_ext)?;
+        Ok(self)
+    }
+
+    /// Returns true if the user confirms each action in turn, which is not the
+    /// case if the plan is executed without confirmation, reviewed, or not
+    /// executed at all.
+    fn confirms_each(&self) -> bool {
+        self.confirm == Confirmation::Each && !self.yes && !self.review && !self.dry_run
+    }
 }
 
 impl Speaker for MassRenameArgs {
@@ -130,24 +428,53 @@ impl Speaker for MassRenameArgs {
     }
 }
 
-pub(crate) fn run(config: &MassRenameArgs) -> Result<(), Box<dyn Error + '_>> {
+pub(crate) fn run(config: &MassRenameArgs) -> Result<(), Box<dyn Error>> {
+    diff::set_color(config.color);
     if config.verbosity() >= Verbosity::Silent {
         println!("Verbosity: {:?}", config.verbosity())
     }
 
-    // Parse instructions
-    let program = parse_with_lalrpop(config)?;
-    if config.verbosity() >= Verbosity::Debug {
-        println!("{:#?}", &program);
-    }
+    let mut plan = if config.auto {
+        create_auto_plan(config)?
+    } else {
+        // Parse instructions
+        let input = config.input.as_deref().unwrap_or_default();
+        let program = parse_with_lalrpop(input, &config.programs)?;
+        if config.verbosity() >= Verbosity::Debug {
+            println!("{:#?}", &program);
+        }
 
-    // Initialize plan
-    let mut plan = create_plan(config)?;
+        // Initialize plan
+        let mut plan = create_plan(config)?;
 
-    // Apply intructions
-    apply_program(config, program, &mut plan)?;
-    if !config.verbosity().is_silent() {
-        plan.present_long()
+        // Apply intructions
+        apply_program(config, program, &mut plan)?;
+        plan
+    };
+    if let Some(order) = config.sort_by {
+        plan.sort_by(order, config.jobs)?;
+    }
+    let plan = if config.review {
+        match review_plan(config, plan)? {
+            Some(plan) => plan,
+            None => return Ok(()),
+        }
+    } else {
+        if config.summary && !config.verbosity().is_silent() {
+            plan.present_summary(!config.no_pager);
+        } else if !config.verbosity().is_silent() {
+            plan.present_long(!config.no_pager);
+        }
+        plan
+    };
+    let plan = if config.confirms_each() {
+        plan.confirm_each()
+    } else {
+        plan
+    };
+    plan.check_collisions()?;
+    if let Some(portability) = config.portable {
+        plan.check_portable(portability)?;
     }
 
     // Maybe create undo script
@@ -159,159 +486,164 @@ pub(crate) fn run(config: &MassRenameArgs) -> Result<(), Box<dyn Error + '_>> {
     }
 
     // Skip if dry run, execute unconditionally or ask for confirmation
-    if !config.dry_run && (config.yes || crate::ocd::user_confirm()) {
+    if !config.dry_run
+        && (config.yes || config.review || config.confirms_each() || crate::ocd::user_confirm())
+    {
         plan.execute()?;
     }
     Ok(())
 }
 
-fn parse_with_lalrpop(config: &MassRenameArgs) -> Result<Program, Box<dyn Error + '_>> {
-    let lexer = crate::ocd::mrn::lalrpop::mrn_lexer::Lexer::new(&config.input);
-    let parser = crate::ocd::mrn::lalrpop::mrn_parser::ProgramParser::new();
-    let instructions = parser.parse(lexer)?;
+/// Lets the user review the plan, and edit the program it is computed from
+/// unless the rule files are used. The entries are listed once, and the
+/// program is applied to them again as it is edited, silently so that no
+/// planning progress is drawn over the interface. The progress of executing
+/// the reviewed plan is still shown.
+fn review_plan(config: &MassRenameArgs, plan: Plan) -> Result<Option<Plan>, Box<dyn Error>> {
+    let input = match &config.input {
+        Some(input) if !config.auto => input.clone(),
+        _ => return review::review(plan, None),
+    };
+    let quiet = MassRenameArgs {
+        silent: true,
+        ..config.clone()
+    };
+    let files = entries(&quiet)?;
+    let replan: Replan = Box::new(|input| replan(&quiet, &files, input));
+    review::review(plan, Some((input, replan)))
+}
+
+/// Computes the plan for the entries from the program edited while
+/// reviewing, listed in the same order as the first plan.
+fn replan(config: &MassRenameArgs, files: &[PathBuf], input: &str) -> Result<Plan, Box<dyn Error>> {
+    let program = parse_with_lalrpop(input, &config.programs)?;
+    if program.is_interactive() {
+        return Err("Interactive reorder cannot be used while reviewing.".into());
+    }
+    let mut plan = Plan::new()
+        .with_git(config.git)
+        .with_progress(config.verbosity().shows_progress())
+        .with_files(files.to_vec());
+    apply_program(config, program, &mut plan)?;
+    if let Some(order) = config.sort_by {
+        plan.sort_by(order, config.jobs)?;
+    }
+    Ok(plan)
+}
+
+fn parse_with_lalrpop(
+    input: &str,
+    programs: &BTreeMap<String, String>,
+) -> Result<Program, Box<dyn Error>> {
+    let input = match input.strip_prefix('@') {
+        Some(name) => format!("call '{name}'"),
+        None => input.to_string(),
+    };
+    let instructions = parse_instructions(&input)?;
+    let instructions = expand_calls(instructions, programs, &mut Vec::new())?;
     let mut program = Program::new(instructions);
     program.check()?;
     Ok(program)
 }
 
-fn create_plan(config: &MassRenameArgs) -> Result<Plan, Box<dyn Error>> {
-    let files = entries(config)?;
-    Ok(Plan::new().with_git(config.git).with_files(files))
-}
-
-/// Navigating the files to operate on differs depending on the combination of options that filter entries, which are:
-/// - whether to recurse the directory tree or not
-/// - whether a glob filter must be applied or not
-/// - whether operations are to be applies to files, directories, or all
-///
-/// recurse | glob | mode | case
-/// --------|------|------|-----
-/// F       | none | f    | 1
-/// F       | none | d    | 2
-/// F       | none | a    | 3
-/// T       | none | f    | 4
-/// T       | none | d    | 5
-/// T       | none | a    | 6
-/// F       | some | f    | 7
-/// T       | some | f    | 7
-/// F       | some | d    | 8
-/// T       | some | d    | 8
-/// T       | some | a    | 9
-/// F       | some | a    | 9
-fn entries(config: &MassRenameArgs) -> Result<Vec<PathBuf>, Box<dyn Error>> {
-    let mut entries_vec: Vec<PathBuf> = Vec::new();
-    match (config.recurse, &config.glob, &config.mode) {
-        (false, None, Mode::Files) => match fs::read_dir(&config.dir) {
-            Ok(iterator) => {
-                for entry in iterator {
-                    match entry {
-                        Ok(file) => {
-                            if file.file_type().unwrap().is_file() {
-                                entries_vec.push(file.path());
-                            }
-                        }
-                        Err(err) => {
-                            return Err(format!("Error while listing files: {:?}", err).into())
-                        }
-                    }
-                }
-            }
-            Err(err) => return Err(format!("Error while listing files: {:?}", err).into()),
-        },
-        (false, None, Mode::Directories) => match fs::read_dir(&config.dir) {
-            Ok(iterator) => {
-                for entry in iterator {
-                    match entry {
-                        Ok(file) => {
-                            if file.file_type().unwrap().is_dir() {
-                                entries_vec.push(file.path());
-                            }
-                        }
-                        Err(err) => {
-                            return Err(format!("Error while listing files: {:?}", err).into())
-                        }
-                    }
-                }
-            }
-            Err(err) => return Err(format!("Error while listing files: {:?}", err).into()),
-        },
-        (false, None, Mode::All) => match fs::read_dir(&config.dir) {
-            Ok(iterator) => {
-                for entry in iterator {
-                    match entry {
-                        Ok(file) => {
-                            entries_vec.push(file.path());
-                        }
-                        Err(_err) => return Err(String::from("Error while listing files").into()),
-                    }
-                }
-            }
-            Err(_err) => return Err(String::from("Error while listing files").into()),
-        },
-        (true, None, Mode::Files) => {
-            let iter = WalkDir::new(&config.dir).into_iter();
-            for entry in iter {
-                match entry {
-                    Ok(entry) => {
-                        if entry.file_type().is_file() {
-                            entries_vec.push(entry.path().to_path_buf());
-                        }
-                    }
-                    Err(_err) => return Err(String::from("Error listing files").into()),
-                }
-            }
-        }
-        (true, None, Mode::Directories) => {
-            let iter = WalkDir::new(&config.dir).into_iter();
-            for entry in iter {
-                match entry {
-                    Ok(entry) => {
-                        if entry.file_type().is_dir() {
-                            entries_vec.push(entry.path().to_path_buf());
-                        }
-                    }
-                    Err(_err) => return Err(String::from("Error listing files").into()),
+fn parse_instructions(input: &str) -> Result<Vec<Instruction>, Box<dyn Error>> {
+    let lexer = crate::ocd::mrn::lalrpop::mrn_lexer::Lexer::new(input);
+    let parser = crate::ocd::mrn::lalrpop::mrn_parser::ProgramParser::new();
+    Ok(parser.parse(lexer)?)
+}
+
+/// Replaces every `call` instruction with the instructions of the named
+/// program, recursively. The stack holds the names of the programs being
+/// expanded, to detect programs that call themselves.
+fn expand_calls(
+    instructions: Vec<Instruction>,
+    programs: &BTreeMap<String, String>,
+    stack: &mut Vec<String>,
+) -> Result<Vec<Instruction>, Box<dyn Error>> {
+    let mut expanded = Vec::new();
+    for instruction in instructions {
+        match instruction {
+            Instruction::Call(name) => {
+                if stack.contains(&name) {
+                    return Err(format!("Program '{name}' calls itself.").into());
                 }
+                let input = programs
+                    .get(&name)
+                    .ok_or_else(|| format!("No program named '{name}' in the configuration."))?;
+                let instructions = parse_instructions(input)
+                    .map_err(|err| format!("Error in program '{name}': {err}"))?;
+                stack.push(name);
+                expanded.extend(expand_calls(instructions, programs, stack)?);
+                stack.pop();
             }
+            Instruction::If {
+                condition,
+                instructions,
+            } => expanded.push(Instruction::If {
+                condition,
+                instructions: expand_calls(instructions, programs, stack)?,
+            }),
+            Instruction::Unless {
+                condition,
+                instructions,
+            } => expanded.push(Instruction::Unless {
+                condition,
+                instructions: expand_calls(instructions, programs, stack)?,
+            }),
+            instruction => expanded.push(instruction),
         }
-        (true, None, Mode::All) => {
-            let iter = WalkDir::new(&config.dir).into_iter();
-            for entry in iter {
-                entries_vec.push(entry.unwrap().path().to_path_buf());
-            }
-        }
-        (_, Some(ref glob_input), Mode::Files) => {
-            let mut path = config.dir.clone();
-            path.push(glob_input);
-            let glob_path = path.as_path().to_str().unwrap();
-            for entry in glob::glob(glob_path).unwrap().filter_map(Result::ok) {
-                let metadata = fs::metadata(&entry).unwrap();
-                if metadata.is_file() {
-                    entries_vec.push(entry);
-                }
-            }
+    }
+    Ok(expanded)
+}
+
+fn create_plan(config: &MassRenameArgs) -> Result<Plan, Box<dyn Error>> {
+    let files = entries(config)?;
+    Ok(Plan::new()
+        .with_git(config.git)
+        .with_progress(config.verbosity().shows_progress())
+        .with_files(files))
+}
+
+/// Creates a plan by applying to each group of entries the program in the rule
+/// file governing them.
+fn create_auto_plan(config: &MassRenameArgs) -> Result<Plan, Box<dyn Error>> {
+    let mut plan = Plan::new()
+        .with_git(config.git)
+        .with_progress(config.verbosity().shows_progress());
+    for (rule_path, (rules, files)) in rules::entries(config)? {
+        if config.verbosity() >= Verbosity::Medium {
+            println!(
+                "Applying {} to {} entries",
+                rule_path.display(),
+                files.len()
+            );
         }
-        (_, Some(ref glob_input), Mode::Directories) => {
-            let mut path = config.dir.clone();
-            path.push(glob_input);
-            let glob_path = path.as_path().to_str().unwrap();
-            for entry in glob::glob(glob_path).unwrap().filter_map(Result::ok) {
-                let metadata = fs::metadata(&entry).unwrap();
-                if metadata.is_dir() {
-                    entries_vec.push(entry);
-                }
-            }
+        let program = parse_with_lalrpop(&rules.program, &config.programs)
+            .map_err(|err| format!("Error in {}: {}", rule_path.display(), err))?;
+        if config.verbosity() >= Verbosity::Debug {
+            println!("{:#?}", &program);
         }
-        (_, Some(ref glob_input), Mode::All) => {
-            let mut path = config.dir.clone();
-            path.push(glob_input);
-            let glob_path = path.as_path().to_str().unwrap();
-            for entry in glob::glob(glob_path).unwrap().filter_map(Result::ok) {
-                entries_vec.push(entry);
-            }
+        let mut rule_plan = Plan::new().with_files(files);
+        apply_program(config, program, &mut rule_plan)?;
+        for (src, action) in rule_plan.actions {
+            plan.insert(src, action);
         }
+        plan.skipped.extend(rule_plan.skipped);
+        plan.dirs.extend(rule_plan.dirs);
     }
-    Ok(entries_vec)
+    Ok(plan)
+}
+
+/// Lists the entries to operate on, filtered by mode, glob, exclusions and depth.
+fn entries(config: &MassRenameArgs) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    Walker::new(&config.dir, &config.walk)
+        .with_mode(config.mode)
+        .with_recurse(config.recurse)
+        .with_git(config.git)
+        .with_hidden(true)
+        .with_glob(config.glob.as_deref())
+        .with_progress(config.verbosity().shows_progress())
+        .entries()
 }
 
 fn apply_program(
@@ -319,111 +651,448 @@ fn apply_program(
     program: Program,
     plan: &mut Plan,
 ) -> Result<(), Box<dyn Error>> {
+    let hashes = if program.uses_sha() {
+        hash_files(config, plan)?
+    } else {
+        Hashes::new()
+    };
+    let steps = program.instructions().len() * plan.actions.len();
+    // The bar would be drawn over the questions of an interactive reorder.
+    let bar = progress::bar(
+        config.verbosity().shows_progress() && !program.is_interactive(),
+        steps as u64,
+        "Planning",
+    );
+    let sequences = number_files(
+        config,
+        plan,
+        &program.sequence_orders(config.sort_by.unwrap_or_default()),
+    )?;
+    let unnumbered = Positions::new();
+    let mut skipped = BTreeMap::new();
     for instruction in program.instructions() {
-        for (index, (src, action)) in plan.actions.iter_mut().enumerate() {
+        for (src, action) in plan.actions.iter_mut() {
+            bar.inc(1);
+            if skipped.contains_key(src) {
+                continue;
+            }
             if config.verbosity() == Verbosity::Debug {
                 println!(
                     "--------------------------------------------------------------------------------"
                 );
                 println!("Applying");
-                println!("    index:       {}", index);
+                println!("    positions:   {:?}", sequences.get(src));
                 println!("    src:         {:?}", src);
                 println!("    action:      {}", action);
                 println!("    instruction: {}", instruction);
             }
-            apply_instruction(config, index, src.as_path(), instruction, action);
+            let positions = sequences.get(src).unwrap_or(&unnumbered);
+            let sha = hashes.get(src).map(String::as_str);
+            if let Err(reason) = apply_instruction(config, positions, sha, instruction, action) {
+                skipped.insert(src.clone(), reason);
+            }
+        }
+    }
+    bar.finish_and_clear();
+    // Skipped entries keep their names.
+    for (src, reason) in skipped {
+        plan.actions
+            .insert(src.clone(), Action::Rename { path: src.clone() });
+        plan.skipped.insert(src, reason);
+    }
+    if config.scope == Scope::Path {
+        check_within(config, plan)?;
+    }
+    if let Some(portability) = config.portable {
+        for (src, action) in plan.actions.iter_mut() {
+            if !plan.skipped.contains_key(src) {
+                apply_portable(config, portability, action);
+            }
         }
+        // Checked before unchanged entries are dropped, as their names may
+        // not be portable either.
+        plan.check_portable(portability)?;
     }
     plan.clean();
+    plan.record_missing_dirs();
     Ok(())
 }
 
+/// The SHA-256 digests of the contents of the files in a plan.
+type Hashes = BTreeMap<PathBuf, String>;
+
+/// Hashes the files in the plan on a pool of worker threads. Files that cannot
+/// be read are left out.
+fn hash_files(config: &MassRenameArgs, plan: &Plan) -> Result<Hashes, Box<dyn Error>> {
+    let paths: Vec<&PathBuf> = plan.actions.keys().collect();
+    let bar = progress::bar(
+        config.verbosity().shows_progress(),
+        paths.len() as u64,
+        "Hashing",
+    );
+    let hashes = crate::ocd::with_jobs(config.jobs, || {
+        paths
+            .par_iter()
+            .filter_map(|path| {
+                bar.inc(1);
+                Some(((*path).clone(), pattern_match::sha256(path).ok()?))
+            })
+            .collect()
+    });
+    bar.finish_and_clear();
+    hashes
+}
+
+/// The positions of the files in a plan, in each order their sequence numbers
+/// follow.
+type Sequences = BTreeMap<PathBuf, Positions>;
+
+/// The positions of a file in each order its sequence numbers follow.
+pub(crate) type Positions = BTreeMap<Order, crate::ocd::order::Position>;
+
+/// Numbers the entries of each directory in the plan in the orders, reading
+/// their dates or sizes on a pool of worker threads.
+fn number_files(
+    config: &MassRenameArgs,
+    plan: &Plan,
+    orders: &[Order],
+) -> Result<Sequences, Box<dyn Error>> {
+    let mut sequences = Sequences::new();
+    for order in orders {
+        let paths = plan.actions.keys().cloned().collect();
+        let positions =
+            crate::ocd::with_jobs(config.jobs, || crate::ocd::order::positions(paths, *order))?;
+        for (path, position) in positions {
+            sequences.entry(path).or_default().insert(*order, position);
+        }
+    }
+    Ok(sequences)
+}
+
+/// Applies the instruction to the action, returning why the entry is skipped
+/// if it cannot be.
 fn apply_instruction(
     config: &MassRenameArgs,
-    index: usize,
-    src: &Path,
+    positions: &Positions,
+    sha: Option<&str>,
     instruction: &Instruction,
     action: &mut Action,
-) {
+) -> Result<(), String> {
+    // Guarded blocks apply their instructions in order, only if their condition holds.
+    let guarded = match instruction {
+        Instruction::If {
+            condition,
+            instructions,
+        } => Some((
+            condition_holds(condition, &config.splitter, action),
+            instructions,
+        )),
+        Instruction::Unless {
+            condition,
+            instructions,
+        } => Some((
+            !condition_holds(condition, &config.splitter, action),
+            instructions,
+        )),
+        _ => None,
+    };
+    if let Some((holds, instructions)) = guarded {
+        if holds {
+            for instruction in instructions {
+                apply_instruction(config, positions, sha, instruction, action)?;
+            }
+        }
+        return Ok(());
+    }
+
     if let Action::Rename { ref mut path } = action {
-        let filename = path.file_stem().unwrap();
-        let filename = filename.to_str().unwrap();
+        if let Instruction::Transcode(label) = instruction {
+            apply_transcode(path, label);
+            return Ok(());
+        }
+        // Names that are not valid UTF-8 are left alone until transcoded.
+        let Some(filename) = scope_text(config, path) else {
+            return Ok(());
+        };
+        let filename = filename.as_str();
         match instruction {
             Instruction::Sanitize => {
                 let filename = apply_sanitize(filename);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
+            }
+            Instruction::SanitizeUnicode => {
+                let filename = apply_sanitize_unicode(filename);
+                set_scope_text(config, path, filename);
+            }
+            Instruction::NormalizeComposed => {
+                let filename = filename.nfc().collect();
+                set_scope_text(config, path, filename);
+            }
+            Instruction::NormalizeDecomposed => {
+                let filename = filename.nfd().collect();
+                set_scope_text(config, path, filename);
+            }
+            Instruction::StripDiacritics => {
+                let filename = apply_strip_diacritics(filename);
+                set_scope_text(config, path, filename);
+            }
+            Instruction::Transliterate => {
+                let filename = deunicode::deunicode(filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::CaseLower => {
                 let filename = apply_lower_case(filename);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::CaseUpper => {
                 let filename = apply_upper_case(filename);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::CaseTitle => {
-                let filename = apply_title_case(filename);
-                crate::ocd::rename_file(path, filename);
+                let filename = apply_title_case(filename, &config.title_case);
+                set_scope_text(config, path, filename);
             }
             Instruction::CaseSentence => {
-                let filename = apply_sentence_case(filename);
-                crate::ocd::rename_file(path, filename);
+                let filename = apply_sentence_case(filename, &config.title_case);
+                set_scope_text(config, path, filename);
             }
             Instruction::JoinCamel => {
                 let filename = apply_join_camel_case(filename);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
+            }
+            Instruction::JoinLowerCamel => {
+                let filename = apply_join_lower_camel_case(filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::JoinSnake => {
                 let filename = apply_join_snake_case(filename);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
+            }
+            Instruction::JoinScreamingSnake => {
+                let filename = apply_join_screaming_snake_case(filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::JoinKebab => {
                 let filename = apply_join_kebab_case(filename);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
+            }
+            Instruction::JoinTrain => {
+                let filename = apply_join_train_case(filename);
+                set_scope_text(config, path, filename);
+            }
+            Instruction::JoinDot => {
+                let filename = apply_join_dot_case(filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::SplitCamel => {
                 let filename = apply_split_camel_case(filename);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::SplitSnake => {
                 let filename = apply_split_snake_case(filename);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::SplitKebab => {
                 let filename = apply_split_kebab_case(filename);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::Replace { pattern, replace } => {
                 let filename = apply_replace(filename, pattern, replace);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::Insert { position, text } => {
                 let filename = apply_insert(filename, text, position);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::Delete { from, to } => {
                 let filename = apply_delete(filename, *from, to);
-                crate::ocd::rename_file(path, filename);
+                set_scope_text(config, path, filename);
             }
             Instruction::PatternMatch {
                 match_pattern: pattern,
                 replace_pattern: replace,
             } => {
-                let filename = pattern_match::apply(config, index, src, filename, pattern, replace);
-                crate::ocd::rename_file(path, filename);
+                let filename =
+                    pattern_match::apply(config, positions, sha, filename, pattern, replace);
+                set_scope_text(config, path, filename);
             }
             Instruction::ExtensionAdd(extension) => {
-                path.set_extension(extension);
+                config.splitter.set_extension(path, extension);
             }
             Instruction::ExtensionRemove => {
-                path.set_extension("");
+                config.splitter.set_extension(path, "");
+            }
+            Instruction::ExtensionChainAdd(extension) => {
+                ExtensionSplitter::new(ExtensionMode::All).set_extension(path, extension);
+            }
+            Instruction::ExtensionChainRemove => {
+                ExtensionSplitter::new(ExtensionMode::All).set_extension(path, "");
             }
             Instruction::Reorder => {
-                let filename = apply_interactive_reorder(filename);
-                crate::ocd::rename_file(path, filename);
+                let once = config.reorder_once.then_some(config.reorder.as_ref());
+                let filename = apply_interactive_reorder(filename, once)?;
+                set_scope_text(config, path, filename);
+            }
+            Instruction::ReorderFields { separator, order } => {
+                let filename = apply_reorder(filename, separator, order)?;
+                set_scope_text(config, path, filename);
+            }
+            Instruction::If { .. } | Instruction::Unless { .. } | Instruction::Transcode(_) => {
+                // Handled above.
+            }
+            Instruction::Call(_) => {
+                // Expanded when the program is parsed.
             }
         };
     }
+    Ok(())
+}
+
+/// Returns the part of the path selected by the scope, unless it is not valid
+/// UTF-8.
+fn scope_text(config: &MassRenameArgs, path: &Path) -> Option<String> {
+    let name = path.file_name().unwrap_or_default().to_str()?;
+    match config.scope {
+        Scope::Stem => Some(String::from(config.splitter.stem(path))),
+        Scope::Name => Some(String::from(name)),
+        Scope::Ext => Some(String::from(config.splitter.extension(path))),
+        Scope::Path => {
+            let relative = path.strip_prefix(&config.dir).unwrap_or(path);
+            relative.to_str().map(String::from)
+        }
+    }
+}
+
+/// Replaces the part of the path selected by the scope with the given text.
+fn set_scope_text(config: &MassRenameArgs, path: &mut PathBuf, text: String) {
+    match config.scope {
+        Scope::Stem => crate::ocd::rename_file(path, text, &config.splitter),
+        Scope::Name => path.set_file_name(text),
+        Scope::Ext => config.splitter.set_extension(path, &text),
+        Scope::Path => *path = join_within(&config.dir, Path::new(&text)),
+    }
+}
+
+/// Joins the path to the directory, resolving its `.` and `..` components.
+/// Components that lead out of the directory are kept, for the plan to be
+/// rejected by `check_within`.
+fn join_within(dir: &Path, relative: &Path) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    let mut depth = 0;
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => {
+                path.push(name);
+                depth += 1;
+            }
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => {
+                path.pop();
+                depth -= 1;
+            }
+            component => {
+                path.push(component);
+                depth = 0;
+            }
+        }
+    }
+    path
+}
+
+/// Fails if a rename with `--scope path` would place an entry outside the
+/// directory worked on.
+fn check_within(config: &MassRenameArgs, plan: &Plan) -> Result<(), Box<dyn Error>> {
+    let outside: Vec<String> = plan
+        .actions
+        .iter()
+        .filter(|(_, action)| {
+            !action
+                .path()
+                .strip_prefix(&config.dir)
+                .is_ok_and(|relative| {
+                    !relative
+                        .components()
+                        .any(|component| component == Component::ParentDir)
+                })
+        })
+        .map(|(src, action)| format!("{} to {}", src.display(), action.path().display()))
+        .collect();
+    if outside.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Renames out of {}: {}",
+            config.dir.display(),
+            outside.join("; ")
+        )
+        .into())
+    }
+}
+
+/// Evaluates a condition against the current destination of a rename action.
+fn condition_holds(condition: &Condition, splitter: &ExtensionSplitter, action: &Action) -> bool {
+    if let Action::Rename { path } = action {
+        match condition {
+            Condition::Match(regex) => path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| regex.is_match(name)),
+            Condition::Extension(extension) => splitter
+                .extension(path)
+                .eq_ignore_ascii_case(extension.trim_start_matches('.')),
+        }
+    } else {
+        false
+    }
+}
+
+/// Rewrites the new name of a rename action to follow the rules of the given
+/// filesystem, and with `--scope path` the directories leading to it as well.
+/// Names that are not valid UTF-8 are left unchanged.
+fn apply_portable(config: &MassRenameArgs, portability: Portability, action: &mut Action) {
+    let Action::Rename { ref mut path } = action else {
+        return;
+    };
+    let rewrite = |name: &OsStr| match name.to_str() {
+        Some(name) => PathBuf::from(portability.rewrite(name, &config.splitter)),
+        None => PathBuf::from(name),
+    };
+    if config.scope == Scope::Path {
+        if let Ok(relative) = path.strip_prefix(&config.dir) {
+            *path =
+                relative
+                    .components()
+                    .fold(config.dir.clone(), |path, component| match component {
+                        Component::Normal(name) => path.join(rewrite(name)),
+                        component => path.join(component),
+                    });
+            return;
+        }
+    }
+    if let Some(name) = path.file_name() {
+        let name = rewrite(name);
+        path.set_file_name(name);
+    }
+}
+
+/// Decodes a file name that is not valid UTF-8 from a legacy encoding, such as
+/// `cp1252` or `shift_jis`. Names that are valid UTF-8 already, or are not
+/// valid in the encoding either, are left unchanged.
+fn apply_transcode(path: &mut PathBuf, label: &str) {
+    // The program has been checked, so the encoding is known to exist.
+    let encoding = Encoding::for_label(label.as_bytes()).unwrap();
+    if let Some(name) = transcode(path.file_name().unwrap_or_default(), encoding) {
+        path.set_file_name(name);
+    }
+}
+
+fn transcode(name: &OsStr, encoding: &'static Encoding) -> Option<String> {
+    if name.to_str().is_some() {
+        return None;
+    }
+    encoding
+        .decode_without_bom_handling_and_without_replacement(name.as_encoded_bytes())
+        .map(String::from)
 }
 
 fn apply_sanitize(filename: &str) -> String {
@@ -437,6 +1106,30 @@ fn apply_sanitize(filename: &str) -> String {
     all.join(" ")
 }
 
+/// Keeps the runs of letters and digits from any script, and the marks
+/// combined with them, separated by spaces.
+fn apply_sanitize_unicode(filename: &str) -> String {
+    static ALPHANUMERIC_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"[\p{L}\p{M}\p{N}]+").unwrap());
+
+    let all: Vec<&str> = ALPHANUMERIC_REGEX
+        .find_iter(filename)
+        .map(|found| found.as_str())
+        .collect();
+    all.join(" ")
+}
+
+/// Removes the accents and other marks combined with letters, keeping the
+/// letters themselves. Letters without a decomposition, such as `ø` or `ł`,
+/// are left unchanged, `tl` transliterates those.
+fn apply_strip_diacritics(filename: &str) -> String {
+    filename
+        .nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .nfc()
+        .collect()
+}
+
 fn apply_lower_case(filename: &str) -> String {
     filename.to_lowercase()
 }
@@ -445,81 +1138,117 @@ fn apply_upper_case(filename: &str) -> String {
     filename.to_uppercase()
 }
 
-fn apply_title_case(filename: &str) -> String {
-    // Original
-    // let mut titlecase_words = Vec::new();
-    // for word in filename.split_whitespace() {
-    //     let titlecase_word = titlecase_word(word);
-    //     titlecase_words.push(titlecase_word);
-    // }
-    // titlecase_words.join(" ")
-
-    // An alternative is this single-line implementation:
-    // voca_rs::case::title_case(filename)
-    // but it doesn't have the same behavior.
+fn apply_title_case(filename: &str, title_case: &TitleCase) -> String {
+    title_case.title(filename)
+}
 
-    filename.to_title_case()
+fn apply_sentence_case(filename: &str, title_case: &TitleCase) -> String {
+    title_case.sentence(filename)
 }
 
-fn apply_sentence_case(filename: &str) -> String {
-    // Original
-    let words: Vec<&str> = filename.split_whitespace().collect();
-    if let Some((first_word, remaining_words)) = words.split_first() {
-        let titlecase_word = titlecase_word(first_word);
-        let mut sentencecase_words = vec![titlecase_word];
-        for word in remaining_words {
-            sentencecase_words.push(word.to_lowercase());
-        }
-        sentencecase_words.join(" ")
-    } else {
-        String::from(filename)
-    }
+// Joins only break the name on whitespace and keep the characters of each
+// word, including digits and punctuation, and their case unless the style
+// requires otherwise. Splits only break the name on their own separator.
 
-    // An alternative is this single-line implementation:
-    // voca_rs::case::capitalize(filename, true)
-    // but it doesn't have the same behavior.
-    // Split the words in the filename separated by whitespace,
-    // and collect them into a vector so we can call split_first()
+fn apply_join_camel_case(filename: &str) -> String {
+    join_camel(filename, true)
 }
 
-fn titlecase_word(word: &str) -> String {
-    let mut titlecase_word = String::new();
-    let chars: Vec<char> = word.chars().collect();
-    if let Some((first_char, remaining_chars)) = chars.split_first() {
-        for c in first_char.to_uppercase() {
-            titlecase_word.push(c);
-        }
-        for c in remaining_chars {
-            for d in c.to_lowercase() {
-                titlecase_word.push(d);
-            }
-        }
-    }
-    titlecase_word
+fn apply_join_lower_camel_case(filename: &str) -> String {
+    join_camel(filename, false)
 }
 
-fn apply_join_camel_case(filename: &str) -> String {
-    filename.to_upper_camel_case()
+fn apply_join_snake_case(filename: &str) -> String {
+    join(filename, "_", |word| String::from(word))
 }
 
-fn apply_join_snake_case(filename: &str) -> String {
-    filename.to_snake_case()
+fn apply_join_screaming_snake_case(filename: &str) -> String {
+    join(filename, "_", str::to_uppercase)
 }
 
 fn apply_join_kebab_case(filename: &str) -> String {
-    filename.to_kebab_case()
+    join(filename, "-", |word| String::from(word))
+}
+
+fn apply_join_train_case(filename: &str) -> String {
+    join(filename, "-", capitalize)
+}
+
+fn apply_join_dot_case(filename: &str) -> String {
+    join(filename, ".", |word| String::from(word))
 }
 
+fn join(filename: &str, separator: &str, case: impl Fn(&str) -> String) -> String {
+    let words: Vec<String> = filename.split_whitespace().map(case).collect();
+    words.join(separator)
+}
+
+/// Joins the words capitalized, and the first one with a lowercase initial
+/// unless `upper`. Adjacent numbers stay apart, joined by an underscore.
+fn join_camel(filename: &str, upper: bool) -> String {
+    let mut result = String::new();
+    for (index, word) in filename.split_whitespace().enumerate() {
+        let word = if index > 0 || upper {
+            capitalize(word)
+        } else if word.chars().any(char::is_lowercase) {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .into_iter()
+                .flat_map(char::to_lowercase)
+                .chain(chars)
+                .collect()
+        } else {
+            word.to_lowercase()
+        };
+        let ends_with_digit = result.ends_with(|c: char| c.is_ascii_digit());
+        if ends_with_digit && word.starts_with(|c: char| c.is_ascii_digit()) {
+            result.push('_');
+        }
+        result.push_str(&word);
+    }
+    result
+}
+
+/// Capitalizes the first letter of the word, keeping the case of the others.
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .into_iter()
+        .flat_map(char::to_uppercase)
+        .chain(chars)
+        .collect()
+}
+
+/// Inserts a space where a lowercase letter or digit is followed by a capital,
+/// and before the last capital of an acronym followed by a lowercase letter,
+/// as in `XMLHttp`.
 fn apply_split_camel_case(filename: &str) -> String {
-    filename.to_title_case()
+    let chars: Vec<char> = filename.chars().collect();
+    let mut result = String::new();
+    for (index, c) in chars.iter().enumerate() {
+        if index > 0 && c.is_uppercase() {
+            let previous = chars[index - 1];
+            let next_is_lowercase = chars.get(index + 1).is_some_and(|c| c.is_lowercase());
+            if previous.is_lowercase()
+                || previous.is_ascii_digit()
+                || (previous.is_uppercase() && next_is_lowercase)
+            {
+                result.push(' ');
+            }
+        }
+        result.push(*c);
+    }
+    result
 }
 
 fn apply_split_snake_case(filename: &str) -> String {
-    filename.to_title_case()
+    filename.replace('_', " ")
 }
 
 fn apply_split_kebab_case(filename: &str) -> String {
-    filename.to_title_case()
+    filename.replace('-', " ")
 }
 
 fn apply_replace(filename: &str, pattern: &ReplaceArg, replace: &ReplaceArg) -> String {
@@ -570,9 +1299,21 @@ fn apply_delete(filename: &str, from_idx: usize, to: &Position) -> String {
     s
 }
 
-fn apply_interactive_reorder(filename: &str) -> String {
+/// Reorders the space-separated fields of the name in the order the user
+/// gives, or the order given for the first name if it is asked once.
+fn apply_interactive_reorder(
+    filename: &str,
+    once: Option<&OnceLock<Vec<usize>>>,
+) -> Result<String, String> {
+    if let Some(order) = once.and_then(OnceLock::get) {
+        return apply_reorder(filename, " ", order);
+    }
+
     // split filename into fields
     let fields: Vec<_> = filename.split(' ').collect();
+    if fields.len() == 1 {
+        return Err(String::from("has 1 field, nothing to reorder"));
+    }
 
     // print each substring with its index below
     let mut idx_line = String::new();
@@ -585,32 +1326,54 @@ fn apply_interactive_reorder(filename: &str) -> String {
     println!("    {}", idx_line);
 
     // read user input & process  into a series of indices
-    let input = crate::ocd::user_input();
-    let input = input.split(' ').map(str::parse::<usize>);
-
-    // all the integers must be parseable and also valid indexes into fields.
-    if input
-        .clone()
-        .any(|e| e.is_err() || e.as_ref().unwrap() > &fields.len())
-    {
-        panic!("Unable to parse user input or invalid index.")
+    let order = user_order(fields.len()).ok_or("no field order was given")?;
+    if let Some(once) = once {
+        let _ = once.set(order.clone());
     }
-    let input: Vec<_> = input.map(|e| e.unwrap()).collect();
+    apply_reorder(filename, " ", &order)
+}
 
-    // generate new string
-    let mut result = String::new();
-    for i in input.iter().take(input.len() - 1) {
-        result.push_str(fields[i - 1]);
-        result.push(' ');
+/// Joins the fields of the name, split on the separator, in the given order,
+/// numbered from 1. Fields may be left out or repeated. Fails if the name has
+/// fewer fields than the order refers to.
+fn apply_reorder(filename: &str, separator: &str, order: &[usize]) -> Result<String, String> {
+    let fields: Vec<&str> = filename.split(separator).collect();
+    let highest = order.iter().max().copied().unwrap_or_default();
+    if highest > fields.len() {
+        let plural = if fields.len() == 1 { "" } else { "s" };
+        return Err(format!(
+            "has {} field{plural}, but field {highest} is reordered",
+            fields.len()
+        ));
     }
-    result.push_str(fields[*input.last().unwrap() - 1]);
+    let reordered: Vec<&str> = order.iter().map(|field| fields[field - 1]).collect();
+    Ok(reordered.join(separator))
+}
 
-    result
+/// Asks the user for the order of the fields, until a valid one is given.
+fn user_order(fields: usize) -> Option<Vec<usize>> {
+    Input::<String>::new()
+        .with_prompt("Order")
+        .validate_with(|input: &String| match parse_field_order(input) {
+            Some(order) if order.iter().all(|field| *field <= fields) => Ok(()),
+            _ => Err(format!(
+                "Enter field numbers from 1 to {fields} separated by spaces, e.g. `2 1`."
+            )),
+        })
+        .interact_text()
+        .ok()
+        .and_then(|input| parse_field_order(&input))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::ocd::config::Config;
+    use crate::ocd::Cli;
+    use crate::ocd::OcdCommand;
+    use clap::CommandFactory;
+    use clap::FromArgMatches;
+    use clap::Parser;
 
     macro_rules! test {
         ($t:ident : $s1:expr => $s2:expr) => {
@@ -621,20 +1384,28 @@ mod test {
         };
     }
 
+    test!(sanitize_unicode_test:
+        apply_sanitize_unicode("Béla Bartók - Москва_写真 (2).mp3") => "Béla Bartók Москва 写真 2 mp3");
+    test!(sanitize_keeps_decomposed_test:
+        apply_sanitize_unicode("Barto\u{301}k_x") => "Barto\u{301}k x");
+    test!(strip_diacritics_test:
+        apply_strip_diacritics("Béla Bartók, Antonín Dvořák, Łódź") => "Bela Bartok, Antonin Dvorak, Łodz");
+    test!(strip_diacritics_decomposed_test:
+        apply_strip_diacritics("Barto\u{301}k") => "Bartok");
     test!(lower_case:
         apply_lower_case("LoWeRcAsE") => "lowercase");
     test!(upper_case_test:
         apply_upper_case("UpPeRcAsE") => "UPPERCASE");
-    // test!(title_case_test_1:
-    //     apply_title_case("A tItLe HaS mUlTiPlE wOrDs") => "A Title Has Multiple Words");
+    test!(title_case_test_1:
+        apply_title_case("A tItLe HaS mUlTiPlE wOrDs", &TitleCase::default()) => "A Title Has Multiple Words");
     test!(sentence_case_test_1:
-        apply_sentence_case("A sEnTeNcE HaS mUlTiPlE wOrDs") => "A sentence has multiple words");
+        apply_sentence_case("A sEnTeNcE HaS mUlTiPlE wOrDs", &TitleCase::default()) => "A sentence has multiple words");
     test!(sentence_case_test_2:
-        apply_sentence_case("a sentence has multiple words") => "A sentence has multiple words");
+        apply_sentence_case("a sentence has multiple words", &TitleCase::default()) => "A sentence has multiple words");
     test!(sentence_case_test_3:
-        apply_sentence_case("A SENTENCE HAS MULTIPLE WORDS") => "A sentence has multiple words");
+        apply_sentence_case("A SENTENCE HAS MULTIPLE WORDS", &TitleCase::default()) => "A sentence has multiple words");
     test!(sentence_case_test_4:
-        apply_sentence_case("A sEnTeNcE HaS mUlTiPlE wOrDs") => "A sentence has multiple words");
+        apply_sentence_case("A sEnTeNcE HaS mUlTiPlE wOrDs", &TitleCase::default()) => "A sentence has multiple words");
     test!(camel_case_join_test:
         apply_join_camel_case("Camel case Join") => "CamelCaseJoin");
     test!(camel_case_split_test_1:
@@ -642,7 +1413,29 @@ mod test {
     test!(camel_case_split_test_2:
         apply_split_camel_case("CamelCaseSplit") => "Camel Case Split");
     test!(camel_case_split_test_3:
-        apply_split_camel_case("XMLHttpRequest") => "Xml Http Request");
+        apply_split_camel_case("XMLHttpRequest") => "XML Http Request");
+    test!(camel_case_split_digits_test:
+        apply_split_camel_case("Track2Name mp3Player") => "Track2 Name mp3 Player");
+    test!(snake_case_split_test:
+        apply_split_snake_case("my_camelCase-file") => "my camelCase-file");
+    test!(kebab_case_split_test:
+        apply_split_kebab_case("my_camelCase-file") => "my_camelCase file");
+    test!(camel_case_join_digits_test:
+        apply_join_camel_case("IMG 2024 01 v1.2 final") => "IMG2024_01V1.2Final");
+    test!(lower_camel_case_join_test:
+        apply_join_lower_camel_case("Lower camel Case") => "lowerCamelCase");
+    test!(lower_camel_case_join_acronym_test:
+        apply_join_lower_camel_case("HTML file") => "htmlFile");
+    test!(snake_case_join_test:
+        apply_join_snake_case(" my  HTML file-2 ") => "my_HTML_file-2");
+    test!(screaming_snake_case_join_test:
+        apply_join_screaming_snake_case("screaming snake Case") => "SCREAMING_SNAKE_CASE");
+    test!(kebab_case_join_test:
+        apply_join_kebab_case("Kebab case v1.2") => "Kebab-case-v1.2");
+    test!(train_case_join_test:
+        apply_join_train_case("train case HTTP") => "Train-Case-HTTP");
+    test!(dot_case_join_test:
+        apply_join_dot_case("dot Case 2") => "dot.Case.2");
     test!(insert_test_1:
         apply_insert("aa bb", " cc", &Position::End) => "aa bb cc");
     test!(insert_test_2:
@@ -683,4 +1476,300 @@ mod test {
         apply_replace("aa_bb_cc_dd", &ReplaceArg::Underscore, &ReplaceArg::Period) => "aa.bb.cc.dd");
     test!(replace_under_space_test:
         apply_replace("aa_bb_cc_dd", &ReplaceArg::Underscore, &ReplaceArg::Space) => "aa bb cc dd");
+
+    fn rename(path: &str) -> Action {
+        Action::Rename {
+            path: PathBuf::from(path),
+        }
+    }
+
+    fn scope_test(scope: &str, path: &str, f: fn(&str) -> String) -> PathBuf {
+        let config = Cli::parse_from(vec!["ocd", "mrn", "-d", "root", "--scope", scope, ""]);
+        if let OcdCommand::MassRename(config) = config.command {
+            let mut path = PathBuf::from(path);
+            let text = f(&scope_text(&config, &path).unwrap());
+            set_scope_text(&config, &mut path, text);
+            path
+        } else {
+            panic!()
+        }
+    }
+
+    test!(scope_stem_test:
+        scope_test("stem", "root/Dir/Photo.JPG", apply_lower_case) => PathBuf::from("root/Dir/photo.JPG"));
+    test!(scope_stem_compound_test:
+        scope_test("stem", "root/Dir/Backup.TAR.GZ", apply_lower_case) => PathBuf::from("root/Dir/backup.TAR.GZ"));
+    test!(scope_name_test:
+        scope_test("name", "root/Dir/Photo.JPG", apply_lower_case) => PathBuf::from("root/Dir/photo.jpg"));
+    test!(scope_ext_test:
+        scope_test("ext", "root/Dir/Photo.JPG", apply_lower_case) => PathBuf::from("root/Dir/Photo.jpg"));
+    test!(scope_path_test:
+        scope_test("path", "root/Dir/Photo.JPG", apply_lower_case) => PathBuf::from("root/dir/photo.jpg"));
+
+    test!(scope_path_parent_test:
+        scope_test("path", "root/Dir/a.txt", |_| String::from("./Dir/../b/./a.txt")) => PathBuf::from("root/b/a.txt"));
+
+    fn scope_path_plan(program: &str) -> Result<Plan, Box<dyn Error>> {
+        let config = Cli::parse_from(vec!["ocd", "mrn", "-d", "root", "--scope", "path", ""]);
+        if let OcdCommand::MassRename(config) = config.command {
+            let program = parse_with_lalrpop(program, &BTreeMap::new()).unwrap();
+            let mut plan = Plan::new().with_files(vec![PathBuf::from("root/a.txt")]);
+            apply_program(&config, program, &mut plan)?;
+            Ok(plan)
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn scope_path_absolute_rejected() {
+        assert!(scope_path_plan("i 0 '/'").is_err());
+    }
+
+    #[test]
+    fn scope_path_parent_rejected() {
+        assert!(scope_path_plan("r 'a' '../../../tmp/x'").is_err());
+        let plan = scope_path_plan("r 'a' 'b/../c'").unwrap();
+        assert_eq!(
+            vec![&PathBuf::from("root/c.txt")],
+            plan.actions.values().map(Action::path).collect::<Vec<_>>()
+        );
+    }
+
+    test!(transcode_latin1_test:
+        transcode(OsStr::new("caf\u{e9}.jpg"), encoding_rs::WINDOWS_1252) => None);
+
+    #[cfg(unix)]
+    #[test]
+    fn transcode_test() {
+        use std::os::unix::ffi::OsStrExt;
+        let latin1 = OsStr::from_bytes(b"caf\xe9 \x80.jpg");
+        assert_eq!(
+            Some(String::from("café €.jpg")),
+            transcode(latin1, Encoding::for_label(b"latin1").unwrap())
+        );
+        let shift_jis = OsStr::from_bytes(b"\x8e\xca\x90\x5e.jpg");
+        assert_eq!(
+            Some(String::from("写真.jpg")),
+            transcode(shift_jis, Encoding::for_label(b"shift_jis").unwrap())
+        );
+        let invalid = OsStr::from_bytes(b"\x81.jpg");
+        assert_eq!(None, transcode(invalid, encoding_rs::SHIFT_JIS));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn name_not_utf8_untouched() {
+        use std::os::unix::ffi::OsStrExt;
+        let config = Cli::parse_from(vec!["ocd", "mrn", "cl"]);
+        if let OcdCommand::MassRename(config) = config.command {
+            let path = PathBuf::from("root").join(OsStr::from_bytes(b"CAF\xc9.JPG"));
+            let mut action = Action::Rename { path: path.clone() };
+            let positions = Positions::new();
+            apply_instruction(
+                &config,
+                &positions,
+                None,
+                &Instruction::CaseLower,
+                &mut action,
+            )
+            .unwrap();
+            assert_eq!(&path, action.path());
+            let transcode = Instruction::Transcode(String::from("cp1252"));
+            apply_instruction(&config, &positions, None, &transcode, &mut action).unwrap();
+            apply_instruction(
+                &config,
+                &positions,
+                None,
+                &Instruction::CaseLower,
+                &mut action,
+            )
+            .unwrap();
+            assert_eq!(&PathBuf::from("root/café.JPG"), action.path());
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn portable_path_test() {
+        let config = Cli::parse_from(vec![
+            "ocd",
+            "mrn",
+            "--dir",
+            "root",
+            "--scope",
+            "path",
+            "--portable",
+            "windows",
+            "cl",
+        ]);
+        if let OcdCommand::MassRename(config) = config.command {
+            let portability = config.portable.unwrap();
+            let mut action = Action::Rename {
+                path: PathBuf::from("root/Live: 1999./con.mp3"),
+            };
+            apply_portable(&config, portability, &mut action);
+            assert_eq!(&PathBuf::from("root/Live_ 1999/con_.mp3"), action.path());
+        } else {
+            panic!()
+        }
+    }
+
+    test!(reorder_test:
+        apply_reorder("Artist Title 2024", " ", &[3, 1, 2]) => Ok(String::from("2024 Artist Title")));
+    test!(reorder_drop_and_repeat_test:
+        apply_reorder("2024-01-05-extra", "-", &[3, 3, 1]) => Ok(String::from("05-05-2024")));
+    test!(reorder_too_few_fields_test:
+        apply_reorder("Artist Title", " ", &[3, 1, 2]) => Err(String::from("has 2 fields, but field 3 is reordered")));
+    test!(parse_field_order_test:
+        parse_field_order(" 3 1  2 ") => Some(vec![3, 1, 2]));
+    test!(parse_field_order_invalid_test:
+        [parse_field_order("0 1"), parse_field_order("2 x"), parse_field_order("")] => [None, None, None]);
+    test!(invalid_field_order_test:
+        parse_with_lalrpop("o '2 x'", &BTreeMap::new()).is_err() => true);
+    test!(empty_separator_test:
+        parse_with_lalrpop("o '' '2 1'", &BTreeMap::new()).is_err() => true);
+
+    #[test]
+    fn auto_plan_records_missing_dirs() {
+        let dir = std::env::temp_dir().join(format!("ocd-auto-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(".ocdrc"), "program = \"i 0 'new/'\"").unwrap();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        let dir_arg = dir.to_str().unwrap();
+        let args = vec![
+            "ocd", "mrn", "--auto", "--silent", "--scope", "path", "-d", dir_arg,
+        ];
+        if let OcdCommand::MassRename(config) = Cli::parse_from(args).command {
+            let plan = create_auto_plan(&config).unwrap();
+            assert_eq!(
+                vec![&dir.join("new/a.txt")],
+                plan.actions.values().map(Action::path).collect::<Vec<_>>()
+            );
+            assert!(plan.dirs.contains(&dir.join("new")));
+        } else {
+            panic!()
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replan_sorts_entries() {
+        let dir = std::env::temp_dir().join(format!("ocd-replan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "aaa").unwrap();
+        std::fs::write(dir.join("b.txt"), "b").unwrap();
+        let dir_arg = dir.to_str().unwrap();
+        let args = vec!["ocd", "mrn", "--sort-by", "size", "-d", dir_arg, ""];
+        if let OcdCommand::MassRename(config) = Cli::parse_from(args).command {
+            let files = vec![dir.join("a.txt"), dir.join("b.txt")];
+            let plan = replan(&config, &files, "i 0 'x'").unwrap();
+            assert_eq!(
+                vec![&dir.join("b.txt"), &dir.join("a.txt")],
+                plan.listed()
+                    .into_iter()
+                    .map(|(src, _)| src)
+                    .collect::<Vec<_>>()
+            );
+        } else {
+            panic!()
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reorder_skips_names() {
+        let config = Cli::parse_from(vec!["ocd", "mrn", "o '3 1 2'"]);
+        if let OcdCommand::MassRename(config) = config.command {
+            let program = parse_with_lalrpop("o '3 1 2',cu", &BTreeMap::new()).unwrap();
+            let mut plan = Plan::new().with_files(vec![
+                PathBuf::from("root/a b c.txt"),
+                PathBuf::from("root/a b.txt"),
+            ]);
+            apply_program(&config, program, &mut plan).unwrap();
+            assert_eq!(
+                vec![&PathBuf::from("root/C A B.txt")],
+                plan.actions.values().map(Action::path).collect::<Vec<_>>()
+            );
+            assert_eq!(
+                Some(&String::from("has 2 fields, but field 3 is reordered")),
+                plan.skipped.get(Path::new("root/a b.txt"))
+            );
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let config = Config::parse("[mrn]\ngit = true\nundo = true\nrecurse = true").unwrap();
+        let matches =
+            Cli::command().get_matches_from(["ocd", "mrn", "--no-git", "-u", "--no-undo", "cl"]);
+        if let OcdCommand::MassRename(args) = Cli::from_arg_matches(&matches).unwrap().command {
+            let args = args
+                .with_config(&config.mrn, matches.subcommand_matches("mrn").unwrap())
+                .unwrap();
+            assert!(!args.git);
+            assert!(!args.undo);
+            assert!(args.recurse);
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn command_line_clears_config() {
+        let config = Config::parse("[mrn]\nportable = \"fat\"\nsort_by = \"exif\"").unwrap();
+        let matches = Cli::command().get_matches_from([
+            "ocd",
+            "mrn",
+            "--portable",
+            "posix",
+            "--no-portable",
+            "--no-sort-by",
+            "cl",
+        ]);
+        if let OcdCommand::MassRename(args) = Cli::from_arg_matches(&matches).unwrap().command {
+            let args = args
+                .with_config(&config.mrn, matches.subcommand_matches("mrn").unwrap())
+                .unwrap();
+            assert_eq!(None, args.portable);
+            assert_eq!(None, args.sort_by);
+        } else {
+            panic!()
+        }
+    }
+
+    test!(unknown_encoding_test:
+        parse_with_lalrpop("tc 'klingon'", &BTreeMap::new()).is_err() => true);
+    test!(invalid_regex_test:
+        parse_with_lalrpop("if match '(' { s }", &BTreeMap::new()).is_err() => true);
+
+    fn programs() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            (String::from("lower"), String::from("cl,rsu")),
+            (String::from("outer"), String::from("s,call 'lower'")),
+            (String::from("loop"), String::from("call 'loop'")),
+        ])
+    }
+
+    test!(expand_calls_test:
+        expand_calls(parse_instructions("cu,call 'outer'").unwrap(), &programs(), &mut Vec::new()).unwrap() =>
+        parse_instructions("cu,s,cl,rsu").unwrap());
+    test!(expand_calls_unknown_test:
+        expand_calls(parse_instructions("call 'nope'").unwrap(), &programs(), &mut Vec::new()).is_err() => true);
+    test!(expand_calls_recursive_test:
+        expand_calls(parse_instructions("call 'loop'").unwrap(), &programs(), &mut Vec::new()).is_err() => true);
+
+    test!(condition_match_test:
+        condition_holds(&Condition::Match(Regex::new(r"^IMG_\d+\.jpg$").unwrap()), &ExtensionSplitter::default(), &rename("dir/IMG_0001.jpg")) => true);
+    test!(condition_no_match_test:
+        condition_holds(&Condition::Match(Regex::new("^IMG_").unwrap()), &ExtensionSplitter::default(), &rename("dir/DSC_0001.jpg")) => false);
+    test!(condition_extension_test:
+        condition_holds(&Condition::Extension(String::from("jpg")), &ExtensionSplitter::default(), &rename("dir/IMG_0001.JPG")) => true);
+    test!(condition_no_extension_test:
+        condition_holds(&Condition::Extension(String::from("jpg")), &ExtensionSplitter::default(), &rename("dir/IMG_0001")) => false);
+    test!(condition_compound_extension:
+        condition_holds(&Condition::Extension(String::from("tar.gz")), &ExtensionSplitter::default(), &rename("dir/backup.tar.gz")) => true);
 }
@@ -1,13 +1,18 @@
 use crate::ocd::mrn::program::ReplacePattern;
 use crate::ocd::mrn::program::ReplacePatternComponent;
 use crate::ocd::mrn::MassRenameArgs;
+use crate::ocd::mrn::Positions;
 use crate::ocd::mrn::Speaker;
 use crate::ocd::Verbosity;
 use rand::distributions::Distribution;
 use rand::distributions::Uniform;
 use regex::Regex;
+use sha2::Digest;
+use sha2::Sha256;
+use std::fs::File;
+use std::io;
+use std::io::Read;
 use std::path::Path;
-use std::process::Command;
 
 pub mod replace_pattern_lexer;
 pub mod replace_pattern_tokens;
@@ -31,6 +36,7 @@ pub fn process_match(match_pattern: String) -> String {
     let match_pattern = match_pattern.replace("{N}", r"([[:digit:]]*)");
     let match_pattern = match_pattern.replace("{X}", r"(.*)");
     let match_pattern = match_pattern.replace("{D}", crate::ocd::date::DATE_FLORB_REGEX_STR);
+    let match_pattern = match_pattern.replace("{T}", crate::ocd::date::DATE_TIME_FLORB_REGEX_STR);
     let mut match_pattern = match_pattern;
     match_pattern.insert(0, '^');
     match_pattern.push('$');
@@ -54,10 +60,32 @@ pub fn process_replace(
     Ok(ReplacePattern { components })
 }
 
+/// Returns the SHA-256 digest of the file's contents, in hexadecimal.
+pub fn sha256(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Applies the pattern match to the file name. `positions` are those of the
+/// entry in each order its sequence numbers follow, and `sha` the digest of the
+/// file's contents, both computed beforehand if the replace pattern uses them.
 pub fn apply(
     config: &MassRenameArgs,
-    index: usize,
-    src: &Path,
+    positions: &Positions,
+    sha: Option<&str>,
     filename: &str,
     match_pattern: &str,
     replace_pattern: &ReplacePattern,
@@ -85,13 +113,8 @@ pub fn apply(
                 new_filename.push_str(literal.as_str());
             }
             ReplacePatternComponent::ShaGenerator => {
-                let output = Command::new("sha256sum")
-                    .args([src.as_os_str()])
-                    .output()
-                    .expect("Error invoking sha256sum.");
-                if output.status.success() {
-                    let sha = String::from_utf8(output.stdout[0..64].to_vec()).unwrap();
-                    new_filename.push_str(&sha);
+                if let Some(sha) = sha {
+                    new_filename.push_str(sha);
                 }
             }
             ReplacePatternComponent::RandomNumberGenerator {
@@ -109,8 +132,15 @@ pub fn apply(
                 start,
                 step,
                 padding,
+                order,
             } => {
-                let num = format!("{:0padding$}", start + (index * step));
+                let position = positions
+                    .get(&order.or(config.sort_by).unwrap_or_default())
+                    .copied()
+                    .unwrap_or_default();
+                let last = start + position.count.saturating_sub(1) * step;
+                let padding = padding.unwrap_or_else(|| last.to_string().len());
+                let num = format!("{:0padding$}", start + (position.index * step));
                 new_filename.push_str(num.as_str());
             }
         }
@@ -126,15 +156,23 @@ fn extract_florb_matches(filename: &str, match_pattern: &str) -> Vec<String> {
                 eprintln!("No captures found for \n    regex {match_pattern:?} \n    in filename {filename:?}");
                 vec![]
             }
-            Some(captures) => captures
-                .iter()
+            Some(captures) => match_regex
+                .capture_names()
+                .zip(captures.iter())
                 .skip(1)
-                .filter(|e| e.is_some())
-                .map(|e| {
-                    let e = e.unwrap().as_str();
+                .filter_map(|(name, e)| e.map(|e| (name, e.as_str())))
+                .map(|(name, e)| {
                     if crate::ocd::date::DATE_FLORB_REGEX.is_match(e) {
-                        let (year, month, day) = crate::ocd::date::regex_date(e).unwrap();
-                        format!("{year}-{month}-{day}")
+                        let (year, month, day, time) = crate::ocd::date::regex_date(e).unwrap();
+                        match time {
+                            // Only `{T}` keeps the time. Colons are not portable
+                            // in filenames, so its components are separated by periods.
+                            Some(time) if name == Some("datetime") => format!(
+                                "{year}-{month}-{day} {:02}.{:02}.{:02}",
+                                time.hour, time.minute, time.second
+                            ),
+                            _ => format!("{year}-{month}-{day}"),
+                        }
                     } else {
                         e.to_string()
                     }
@@ -160,12 +198,14 @@ mod test {
     use crate::ocd::mrn::pattern_match::replace_pattern_tokens;
     use crate::ocd::mrn::pattern_match::replace_pattern_tokens::Token;
     use crate::ocd::mrn::program::ReplacePatternComponent;
+    use crate::ocd::mrn::Positions;
+    use crate::ocd::order::Order;
+    use crate::ocd::order::Position;
     use crate::ocd::Cli;
     use crate::ocd::OcdCommand;
-    use std::path::Path;
 
     fn test_pattern(
-        index: usize,
+        position: Position,
         filename: &str,
         match_pattern_str: &str,
         replace_pattern_str: &str,
@@ -176,10 +216,11 @@ mod test {
             let match_pattern = super::process_match(String::from(match_pattern_str));
             let replace_pattern =
                 super::process_replace(String::from(replace_pattern_str)).unwrap();
+            let positions = Positions::from([(Order::Natural, position)]);
             let result = super::apply(
                 &config,
-                index,
-                Path::new(filename),
+                &positions,
+                None,
                 filename,
                 &match_pattern,
                 &replace_pattern,
@@ -190,6 +231,17 @@ mod test {
         }
     }
 
+    #[test]
+    fn sha256_of_file() {
+        let path = std::env::temp_dir().join(format!("ocd-sha-{}", std::process::id()));
+        std::fs::write(&path, b"abc").unwrap();
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            super::sha256(&path).unwrap()
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
     /*
     case | rng          | start | end | padding | meaning
     1    | {rng}        |    no |  no |      no | random number between 1 and 100
@@ -210,6 +262,8 @@ mod test {
     6    | {sng10,5}   |   yes |   no |     yes |
     7    | {sng10+2}   |   yes |  yes |      no |
     8    | {sng10+2,5} |   yes |  yes |     yes |
+
+    Any of them may end with an order, e.g. {sng:mtime} or {sng10+2,5:exif}.
     */
 
     fn lex(input: &str) -> Vec<replace_pattern_tokens::Token> {
@@ -248,6 +302,18 @@ mod test {
         assert_eq!(expected.as_slice(), result.as_slice());
     }
 
+    #[test]
+    fn florb0_order() {
+        let input = "{sng:exif}b}";
+        let expected = vec![
+            Token::SequentialNumberGenerator,
+            Token::Order(String::from("exif")),
+            Token::Text(String::from("b}")),
+        ];
+        let result = lex(input);
+        assert_eq!(expected.as_slice(), result.as_slice());
+    }
+
     #[test]
     fn florb1() {
         let input = "{1}";
@@ -479,7 +545,8 @@ mod test {
         let expected = vec![ReplacePatternComponent::SequentialNumberGenerator {
             start: 1,
             step: 1,
-            padding: 0,
+            padding: None,
+            order: None,
         }];
         let result = parse(input);
         assert_eq!(expected.as_slice(), result.as_slice());
@@ -504,7 +571,8 @@ mod test {
         let expected = vec![ReplacePatternComponent::SequentialNumberGenerator {
             start: 1,
             step: 1,
-            padding: 5,
+            padding: Some(5),
+            order: None,
         }];
         let result = parse(input);
         assert_eq!(expected.as_slice(), result.as_slice());
@@ -529,7 +597,8 @@ mod test {
         let expected = vec![ReplacePatternComponent::SequentialNumberGenerator {
             start: 1,
             step: 2,
-            padding: 0,
+            padding: None,
+            order: None,
         }];
         let result = parse(input);
         assert_eq!(expected.as_slice(), result.as_slice());
@@ -556,7 +625,8 @@ mod test {
         let expected = vec![ReplacePatternComponent::SequentialNumberGenerator {
             start: 1,
             step: 2,
-            padding: 5,
+            padding: Some(5),
+            order: None,
         }];
         let result = parse(input);
         assert_eq!(expected.as_slice(), result.as_slice());
@@ -580,7 +650,8 @@ mod test {
         let expected = vec![ReplacePatternComponent::SequentialNumberGenerator {
             start: 10,
             step: 1,
-            padding: 0,
+            padding: None,
+            order: None,
         }];
         let result = parse(input);
         assert_eq!(expected.as_slice(), result.as_slice());
@@ -606,7 +677,8 @@ mod test {
         let expected = vec![ReplacePatternComponent::SequentialNumberGenerator {
             start: 10,
             step: 1,
-            padding: 5,
+            padding: Some(5),
+            order: None,
         }];
         let result = parse(input);
         assert_eq!(expected.as_slice(), result.as_slice());
@@ -632,7 +704,8 @@ mod test {
         let expected = vec![ReplacePatternComponent::SequentialNumberGenerator {
             start: 10,
             step: 2,
-            padding: 0,
+            padding: None,
+            order: None,
         }];
         let result = parse(input);
         assert_eq!(expected.as_slice(), result.as_slice());
@@ -660,7 +733,8 @@ mod test {
         let expected = vec![ReplacePatternComponent::SequentialNumberGenerator {
             start: 10,
             step: 2,
-            padding: 5,
+            padding: Some(5),
+            order: None,
         }];
         let result = parse(input);
         assert_eq!(expected.as_slice(), result.as_slice());
@@ -668,13 +742,13 @@ mod test {
 
     #[test]
     fn pattern_match_1() {
-        test_pattern(0, "aa bb", "{X} {X}", "{2} {1}", "bb aa");
+        test_pattern(Position::default(), "aa bb", "{X} {X}", "{2} {1}", "bb aa");
     }
 
     #[test]
     fn pattern_match_2() {
         test_pattern(
-            0,
+            Position::default(),
             "Dave Brubeck - 01. Take five",
             "{X} - {N}. {X}",
             "{1} {2} {3}",
@@ -685,7 +759,7 @@ mod test {
     #[test]
     fn pattern_match_3() {
         test_pattern(
-            0,
+            Position::default(),
             "Bahia Blanca, 21 October 2019",
             "{X}, {D}",
             "{2} {1}",
@@ -696,7 +770,7 @@ mod test {
     #[test]
     fn pattern_match_4() {
         test_pattern(
-            0,
+            Position::default(),
             "Foo 123 B_a_r",
             "{A} {N} {X}",
             "{3} {2} {1}",
@@ -704,14 +778,81 @@ mod test {
         )
     }
 
+    #[test]
+    fn pattern_match_date_time() {
+        test_pattern(
+            Position::default(),
+            "IMG_20240501_142233",
+            "IMG_{T}",
+            "Trip {1}",
+            "Trip 2024-5-1 14.22.33",
+        )
+    }
+
+    #[test]
+    fn pattern_match_date_keeps_time_out() {
+        test_pattern(
+            Position::default(),
+            "IMG_20240501_142233",
+            "IMG_{D}_{N}",
+            "Trip {1}",
+            "Trip 2024-5-1",
+        )
+    }
+
     #[test]
     fn pattern_match_5() {
         test_pattern(
-            0,
+            Position::default(),
             "Bahia Blanca, 21 October 2019, FooBarBaz",
             "{X}, {D}, {X}",
             "{2} {1} {3}",
             "2019-10-21 Bahia Blanca FooBarBaz",
         )
     }
+
+    #[test]
+    fn sng_order_lex() {
+        let input = "{sng,3:mtime}.jpg";
+        let expected = vec![
+            Token::SequentialNumberGenerator,
+            Token::Comma,
+            Token::Integer(3),
+            Token::Order(String::from("mtime")),
+            Token::Text(String::from(".jpg")),
+        ];
+        let result = lex(input);
+        assert_eq!(expected.as_slice(), result.as_slice());
+    }
+
+    #[test]
+    fn sng_order_parse() {
+        let input = "{sng10+2:exif}:x";
+        let expected = vec![
+            ReplacePatternComponent::SequentialNumberGenerator {
+                start: 10,
+                step: 2,
+                padding: None,
+                order: Some(Order::Exif),
+            },
+            ReplacePatternComponent::Literal(String::from(":x")),
+        ];
+        let result = parse(input);
+        assert_eq!(expected.as_slice(), result.as_slice());
+        assert!(super::process_replace(String::from("{sng:color}")).is_err());
+        assert_eq!(
+            vec![ReplacePatternComponent::Literal(String::from(":x}"))],
+            parse(":x}")
+        );
+    }
+
+    #[test]
+    fn sng_padding() {
+        let position = |index, count| Position { index, count };
+        test_pattern(position(2, 12), "a", "{X}", "{1}_{sng}", "a_03");
+        test_pattern(position(2, 9), "a", "{X}", "{1}_{sng}", "a_3");
+        test_pattern(position(4, 5), "a", "{X}", "{sng10+2}", "18");
+        test_pattern(position(2, 12), "a", "{X}", "{sng,4}", "0003");
+        test_pattern(position(2, 12), "a", "{X}", "{sng,0}", "3");
+    }
 }
@@ -2,9 +2,11 @@ use logos::Logos;
 use std::fmt;
 use std::num::ParseIntError;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Default, Debug, Clone, PartialEq)]
 pub enum LexicalError {
     InvalidInteger(ParseIntError),
+    InvalidOrder(String),
     #[default]
     InvalidToken,
 }
@@ -44,9 +46,13 @@ pub enum Token {
     Florb(usize),
     #[regex("[0-9]+", |lex| lex.slice().parse(), priority = 3)]
     Integer(usize),
+    // The order ends a sequential number generator, so it includes the closing
+    // brace, which text may also end with.
+    #[regex(r":[a-z]+\}", |lex| lex.slice().trim_start_matches(':').trim_end_matches('}').to_string(), priority = 3)]
+    Order(String),
     #[regex("[ ]+", |lex| lex.slice().to_string(), priority = 2)]
     Whitespace(String),
-    #[regex("[^ -0123456789{]+", |lex| lex.slice().to_string(), priority = 2)]
+    #[regex(r"[^ ,+\-0-9{}]+\}?", |lex| lex.slice().to_string(), priority = 2)]
     Text(String),
 }
 
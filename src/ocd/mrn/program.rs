@@ -1,3 +1,7 @@
+use crate::ocd::order::Order;
+use clap::ValueEnum;
+use encoding_rs::Encoding;
+use regex::Regex;
 use std::error::Error;
 use std::fmt::Debug;
 
@@ -14,20 +18,110 @@ impl Program {
     }
 
     pub fn check(&mut self) -> Result<(), Box<dyn Error>> {
-        Ok(())
+        check_instructions(&self.0)
     }
+
+    /// Returns true if any pattern match hashes the contents of the files.
+    pub fn uses_sha(&self) -> bool {
+        any_instruction(&self.0, &|instruction| match instruction {
+            Instruction::PatternMatch {
+                replace_pattern, ..
+            } => replace_pattern
+                .components
+                .contains(&ReplacePatternComponent::ShaGenerator),
+            _ => false,
+        })
+    }
+
+    /// Returns the orders sequence numbers follow, `default` for those that
+    /// do not give one.
+    pub fn sequence_orders(&self, default: Order) -> Vec<Order> {
+        Order::value_variants()
+            .iter()
+            .copied()
+            .filter(|order| {
+                any_instruction(&self.0, &|instruction| match instruction {
+                    Instruction::PatternMatch {
+                        replace_pattern, ..
+                    } => replace_pattern.components.iter().any(|component| {
+                        matches!(component, ReplacePatternComponent::SequentialNumberGenerator { order: sequence, .. } if sequence.unwrap_or(default) == *order)
+                    }),
+                    _ => false,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns true if the program asks the user how to reorder file names.
+    pub fn is_interactive(&self) -> bool {
+        any_instruction(&self.0, &|instruction| *instruction == Instruction::Reorder)
+    }
+}
+
+/// Parses the order of the fields of `o`, numbered from 1 and separated by
+/// spaces, as in `3 1 2`.
+pub fn parse_field_order(order: &str) -> Option<Vec<usize>> {
+    let order: Vec<usize> = order
+        .split_whitespace()
+        .map(|field| field.parse().ok().filter(|field| *field > 0))
+        .collect::<Option<_>>()?;
+    (!order.is_empty()).then_some(order)
+}
+
+/// Returns true if the predicate holds for any instruction, including those
+/// inside conditionals.
+fn any_instruction(instructions: &[Instruction], predicate: &dyn Fn(&Instruction) -> bool) -> bool {
+    instructions.iter().any(|instruction| match instruction {
+        Instruction::If { instructions, .. } | Instruction::Unless { instructions, .. } => {
+            any_instruction(instructions, predicate)
+        }
+        instruction => predicate(instruction),
+    })
+}
+
+/// Verifies that the encodings transcoded from are known and that fields are
+/// separated by some text, so that errors are reported before any file is
+/// processed. The regular expressions in conditions are compiled when parsed.
+fn check_instructions(instructions: &[Instruction]) -> Result<(), Box<dyn Error>> {
+    for instruction in instructions {
+        if let Instruction::If { instructions, .. } | Instruction::Unless { instructions, .. } =
+            instruction
+        {
+            check_instructions(instructions)?;
+        }
+        if let Instruction::Transcode(label) = instruction {
+            if Encoding::for_label(label.as_bytes()).is_none() {
+                return Err(format!("Unknown encoding '{label}'.").into());
+            }
+        }
+        if let Instruction::ReorderFields { separator, .. } = instruction {
+            if separator.is_empty() {
+                return Err("The separator of the fields to reorder cannot be empty.".into());
+            }
+        }
+    }
+    Ok(())
 }
 
 #[derive(Debug, PartialEq, strum_macros::Display)]
 pub enum Instruction {
     Sanitize,
+    SanitizeUnicode,
+    NormalizeComposed,
+    NormalizeDecomposed,
+    StripDiacritics,
+    Transliterate,
     CaseLower,
     CaseUpper,
     CaseTitle,
     CaseSentence,
     JoinCamel,
+    JoinLowerCamel,
     JoinSnake,
+    JoinScreamingSnake,
     JoinKebab,
+    JoinTrain,
+    JoinDot,
     SplitCamel,
     SplitSnake,
     SplitKebab,
@@ -49,7 +143,43 @@ pub enum Instruction {
     },
     ExtensionAdd(String),
     ExtensionRemove,
+    ExtensionChainAdd(String),
+    ExtensionChainRemove,
+    Transcode(String),
     Reorder,
+    ReorderFields {
+        separator: String,
+        order: Vec<usize>,
+    },
+    Call(String),
+    If {
+        condition: Condition,
+        instructions: Vec<Instruction>,
+    },
+    Unless {
+        condition: Condition,
+        instructions: Vec<Instruction>,
+    },
+}
+
+/// A condition on the current file name which guards a block of instructions.
+#[derive(Debug)]
+pub enum Condition {
+    /// The file name, including the extension, matches the regular expression.
+    Match(Regex),
+    /// The file extension is equal to the given one, ignoring case.
+    Extension(String),
+}
+
+/// Conditions are equal if their regular expressions are written the same.
+impl PartialEq for Condition {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Condition::Match(a), Condition::Match(b)) => a.as_str() == b.as_str(),
+            (Condition::Extension(a), Condition::Extension(b)) => a == b,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -94,9 +224,12 @@ pub enum ReplacePatternComponent {
         end: usize,
         padding: usize,
     },
+    /// Numbers the entries of each directory in the order, padded to the
+    /// width of the largest number unless the padding is given.
     SequentialNumberGenerator {
         start: usize,
         step: usize,
-        padding: usize,
+        padding: Option<usize>,
+        order: Option<Order>,
     },
 }
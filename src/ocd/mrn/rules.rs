@@ -0,0 +1,204 @@
+//! Per-directory rule files.
+//!
+//! A `.ocdrc` file holds the rules for renaming the entries of the directory it
+//! is in and of its subdirectories, unless a subdirectory has a rule file of its
+//! own. The nearest rule file above `--dir` also applies. Rule files are TOML:
+//!
+//! ```toml
+//! program = "s,cl,rsu"
+//! glob = "*.jpg"
+//! mode = "files"
+//! ignore = ["*.tmp", "Thumbs.db"]
+//! ```
+//!
+//! Only `program` is required. `glob` and `ignore` are matched against the
+//! entry's file name, and `mode` defaults to `files`.
+
+use crate::ocd::mrn::MassRenameArgs;
+use crate::ocd::walk::Walker;
+use crate::ocd::Mode;
+use crate::ocd::Speaker;
+use glob::Pattern;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub(crate) const RULE_FILE_NAME: &str = ".ocdrc";
+
+/// Entries grouped by the path of the rule file governing them.
+pub(crate) type RuleGroups = BTreeMap<PathBuf, (Rules, Vec<PathBuf>)>;
+
+/// The contents of a rule file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rules {
+    pub(crate) program: String,
+    #[serde(default)]
+    glob: Option<String>,
+    #[serde(default)]
+    mode: Option<Mode>,
+    #[serde(default)]
+    ignore: Vec<String>,
+}
+
+/// A rule file with its glob and ignore patterns compiled.
+struct CompiledRules {
+    rules: Rules,
+    glob: Option<Pattern>,
+    ignore: Vec<Pattern>,
+}
+
+impl Rules {
+    fn parse(contents: &str) -> Result<Rules, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    fn load(path: &Path) -> Result<CompiledRules, Box<dyn Error>> {
+        let error = |err: &dyn Error| format!("Error in {}: {}", path.display(), err);
+        let contents = fs::read_to_string(path).map_err(|err| error(&err))?;
+        let rules = Rules::parse(&contents).map_err(|err| error(&err))?;
+        let glob = rules
+            .glob
+            .as_deref()
+            .map(Pattern::new)
+            .transpose()
+            .map_err(|err| error(&err))?;
+        let ignore = rules
+            .ignore
+            .iter()
+            .map(|pattern| Pattern::new(pattern))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| error(&err))?;
+        Ok(CompiledRules {
+            rules,
+            glob,
+            ignore,
+        })
+    }
+}
+
+impl CompiledRules {
+    /// Returns true if the entry is subject to these rules.
+    fn governs(&self, entry: &Path, is_dir: bool) -> bool {
+        let name = match entry.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return false,
+        };
+        let mode_matches = match self.rules.mode.unwrap_or(Mode::Files) {
+            Mode::All => true,
+            Mode::Directories => is_dir,
+            Mode::Files => !is_dir,
+        };
+        mode_matches
+            && self.glob.as_ref().is_none_or(|glob| glob.matches(&name))
+            && !self.ignore.iter().any(|pattern| pattern.matches(&name))
+    }
+}
+
+/// Finds the rule file that governs the entries of each directory, caching the
+/// result for every directory visited.
+struct RuleFinder {
+    governing: HashMap<PathBuf, Option<PathBuf>>,
+}
+
+impl RuleFinder {
+    fn new() -> Self {
+        RuleFinder {
+            governing: HashMap::new(),
+        }
+    }
+
+    /// Returns the path of the nearest rule file in the directory or its ancestors.
+    fn find(&mut self, dir: &Path) -> Option<PathBuf> {
+        if let Some(found) = self.governing.get(dir) {
+            return found.clone();
+        }
+        let candidate = dir.join(RULE_FILE_NAME);
+        let found = if candidate.is_file() {
+            Some(candidate)
+        } else {
+            dir.parent().and_then(|parent| self.find(parent))
+        };
+        self.governing.insert(dir.to_path_buf(), found.clone());
+        found
+    }
+}
+
+/// Lists the entries to operate on grouped by the rule file governing them.
+/// Entries not governed by any rule file are skipped.
+pub(crate) fn entries(config: &MassRenameArgs) -> Result<RuleGroups, Box<dyn Error>> {
+    let mut finder = RuleFinder::new();
+    let mut loaded: BTreeMap<PathBuf, CompiledRules> = BTreeMap::new();
+    let mut grouped: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    let walker = Walker::new(&config.dir, &config.walk)
+        .with_recurse(config.recurse)
+        .with_git(config.git)
+        .with_hidden(true)
+        .with_progress(config.verbosity().shows_progress());
+    for path in walker.entries()? {
+        if path.file_name().is_some_and(|name| name == RULE_FILE_NAME) {
+            continue;
+        }
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        let rule_path = match finder.find(&dir) {
+            Some(rule_path) => rule_path,
+            None => continue,
+        };
+        if !loaded.contains_key(&rule_path) {
+            loaded.insert(rule_path.clone(), Rules::load(&rule_path)?);
+        }
+        if loaded[&rule_path].governs(&path, path.is_dir()) {
+            grouped.entry(rule_path).or_default().push(path);
+        }
+    }
+    Ok(grouped
+        .into_iter()
+        .map(|(rule_path, entries)| {
+            let rules = loaded.remove(&rule_path).unwrap().rules;
+            (rule_path, (rules, entries))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_rules() {
+        let contents = r#"
+            program = "s,cl"
+            glob = "*.jpg"
+            mode = "all"
+            ignore = ["*.tmp"]
+        "#;
+        let rules = Rules::parse(contents).unwrap();
+        assert_eq!("s,cl", rules.program);
+        assert_eq!(Some(String::from("*.jpg")), rules.glob);
+        assert_eq!(Some(Mode::All), rules.mode);
+        assert_eq!(vec![String::from("*.tmp")], rules.ignore);
+    }
+
+    #[test]
+    fn parse_rules_without_program() {
+        assert!(Rules::parse("glob = \"*.jpg\"").is_err());
+    }
+
+    #[test]
+    fn governs() {
+        let rules = CompiledRules {
+            rules: Rules::parse("program = \"s\"").unwrap(),
+            glob: Some(Pattern::new("*.jpg").unwrap()),
+            ignore: vec![Pattern::new("skip*").unwrap()],
+        };
+        assert!(rules.governs(Path::new("dir/a.jpg"), false));
+        assert!(!rules.governs(Path::new("dir/a.png"), false));
+        assert!(!rules.governs(Path::new("dir/skip.jpg"), false));
+        assert!(!rules.governs(Path::new("dir/a.jpg"), true));
+    }
+}
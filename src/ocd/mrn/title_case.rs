@@ -0,0 +1,235 @@
+//! Title and sentence case.
+//!
+//! The text is split into words, made of letters and digits and the
+//! apostrophes within them, and the separators between them, which are left
+//! untouched: `the_dark_side_of_the_moon` becomes `The_Dark_Side_of_the_Moon`.
+//!
+//! Words in capitals, such as `BBC`, are taken to be acronyms and kept, unless
+//! the whole text is in capitals. Roman numerals made of `I`, `V` and `X` are
+//! written in capitals, so that `Rocky ii` becomes `Rocky II`. In title case,
+//! minor words such as `of` or `the` are lowercase unless they start or end the
+//! text or a phrase within it, e.g. after a colon or ` - `.
+
+use regex::Regex;
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+/// The words lowercase in title case by default.
+pub(crate) const DEFAULT_MINOR_WORDS: &[&str] = &[
+    "a", "an", "and", "as", "at", "but", "by", "de", "del", "for", "in", "la", "nor", "of", "on",
+    "or", "the", "to", "vs", "y",
+];
+
+#[derive(Clone, Debug)]
+pub(crate) struct TitleCase {
+    minor_words: HashSet<String>,
+}
+
+impl Default for TitleCase {
+    fn default() -> Self {
+        TitleCase::new(DEFAULT_MINOR_WORDS)
+    }
+}
+
+/// A word of the text, or the separator before it.
+enum Token<'a> {
+    Word(&'a str),
+    Separator(&'a str),
+}
+
+impl TitleCase {
+    /// Creates title casing keeping the given words lowercase.
+    pub(crate) fn new<S: AsRef<str>>(minor_words: &[S]) -> Self {
+        TitleCase {
+            minor_words: minor_words
+                .iter()
+                .map(|word| word.as_ref().to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Capitalizes every word but minor ones.
+    pub(crate) fn title(&self, text: &str) -> String {
+        let shouting = !text.chars().any(char::is_lowercase);
+        let tokens = tokenize(text);
+        let words = tokens
+            .iter()
+            .filter(|token| matches!(token, Token::Word(_)))
+            .count();
+        let mut result = String::new();
+        let mut index = 0;
+        let mut phrase_start = true;
+        for (position, token) in tokens.iter().enumerate() {
+            match token {
+                Token::Separator(separator) => {
+                    result.push_str(separator);
+                    phrase_start |= starts_phrase(separator);
+                }
+                Token::Word(word) => {
+                    index += 1;
+                    let phrase_end = index == words
+                        || matches!(tokens.get(position + 1), Some(Token::Separator(separator)) if ends_phrase(separator));
+                    let minor = !phrase_start
+                        && !phrase_end
+                        && self.minor_words.contains(&word.to_lowercase());
+                    result.push_str(&case_word(word, shouting, !minor));
+                    phrase_start = false;
+                }
+            }
+        }
+        result
+    }
+
+    /// Capitalizes the first word only.
+    pub(crate) fn sentence(&self, text: &str) -> String {
+        let shouting = !text.chars().any(char::is_lowercase);
+        let mut first = true;
+        tokenize(text)
+            .iter()
+            .map(|token| match token {
+                Token::Separator(separator) => String::from(*separator),
+                Token::Word(word) => {
+                    let word = case_word(word, shouting, first);
+                    first = false;
+                    word
+                }
+            })
+            .collect()
+    }
+}
+
+/// Splits the text into words and the separators between them.
+fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let is_word = |i: usize| {
+        let c = chars[i].1;
+        c.is_alphanumeric()
+            || unicode_normalization::char::is_combining_mark(c)
+            || (is_apostrophe(c)
+                && i > 0
+                && chars[i - 1].1.is_alphanumeric()
+                && chars.get(i + 1).is_some_and(|(_, c)| c.is_alphanumeric()))
+    };
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    for i in 1..=chars.len() {
+        let end = chars.get(i).map_or(text.len(), |(index, _)| *index);
+        if i == chars.len() || is_word(i) != is_word(i - 1) {
+            let slice = &text[chars[start].0..end];
+            tokens.push(if is_word(start) {
+                Token::Word(slice)
+            } else {
+                Token::Separator(slice)
+            });
+            start = i;
+        }
+    }
+    tokens
+}
+
+/// Returns the word in capitals if it is an acronym or roman numeral, or else
+/// lowercase, with its first letter capitalized if asked to.
+fn case_word(word: &str, shouting: bool, capitalize: bool) -> String {
+    if is_roman_numeral(word) || (!shouting && is_acronym(word)) {
+        return word.to_uppercase();
+    }
+    let lower = word.to_lowercase();
+    if !capitalize {
+        return lower;
+    }
+    let mut result = String::new();
+    let mut after_apostrophe = false;
+    for (i, c) in lower.chars().enumerate() {
+        if i == 0 || after_apostrophe {
+            result.extend(c.to_uppercase());
+        } else {
+            result.push(c);
+        }
+        // Names such as O'Neil are capitalized after the apostrophe too, but
+        // not contractions such as I'm or Don't.
+        after_apostrophe = i == 1 && is_apostrophe(c) && result.starts_with('O');
+    }
+    result
+}
+
+fn is_acronym(word: &str) -> bool {
+    word.chars().filter(|c| c.is_alphabetic()).count() > 1
+        && !word.chars().any(|c| c.is_lowercase() || is_apostrophe(c))
+}
+
+/// Returns true for the roman numerals from 1 to 39, in any case, which
+/// excludes words such as `mix` or `did` made of the other numeral letters.
+fn is_roman_numeral(word: &str) -> bool {
+    static ROMAN_NUMERAL_REGEX: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"(?i)^X{0,3}(IX|IV|V?I{0,3})$").unwrap());
+    !word.is_empty() && ROMAN_NUMERAL_REGEX.is_match(word)
+}
+
+fn is_apostrophe(c: char) -> bool {
+    c == '\'' || c == '’'
+}
+
+fn starts_phrase(separator: &str) -> bool {
+    separator.contains([':', '(', '[', '{']) || is_spaced_dash(separator)
+}
+
+fn ends_phrase(separator: &str) -> bool {
+    separator.contains([':', ')', ']', '}']) || is_spaced_dash(separator)
+}
+
+/// Returns true for a dash between spaces, as between an artist and a title,
+/// but not a hyphen within a word.
+fn is_spaced_dash(separator: &str) -> bool {
+    separator.contains(['-', '–', '—']) && separator.contains(char::is_whitespace)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    macro_rules! test {
+        ($t:ident : $s1:expr => $s2:expr) => {
+            #[test]
+            fn $t() {
+                assert_eq!($s1, $s2)
+            }
+        };
+    }
+
+    fn title(text: &str) -> String {
+        TitleCase::default().title(text)
+    }
+
+    fn sentence(text: &str) -> String {
+        TitleCase::default().sentence(text)
+    }
+
+    test!(title_minor_words:
+        title("the lord of the rings") => "The Lord of the Rings");
+    test!(title_last_word:
+        title("what the song is about") => "What the Song Is About");
+    test!(title_separators:
+        title("the_dark_side_of_the_moon") => "The_Dark_Side_of_the_Moon");
+    test!(title_phrases:
+        title("the beatles - a day in the life (the remaster)") => "The Beatles - A Day in the Life (The Remaster)");
+    test!(title_hyphen:
+        title("state-of-the-art") => "State-of-the-Art");
+    test!(title_acronyms:
+        title("a NASA documentary by the BBC") => "A NASA Documentary by the BBC");
+    test!(title_shouting:
+        title("A TITLE HAS MULTIPLE WORDS") => "A Title Has Multiple Words");
+    test!(title_apostrophes:
+        title("don't stop me now, i'm o'neil’s") => "Don't Stop Me Now, I'm O'Neil’s");
+    test!(title_roman_numerals:
+        title("rocky iv vs. mix part ii") => "Rocky IV vs. Mix Part II");
+    test!(title_spanish:
+        title("cien años de soledad y la casa") => "Cien Años de Soledad y la Casa");
+    test!(title_custom_minor_words:
+        TitleCase::new(&["der", "und"]).title("der herr der ringe und the hobbit") => "Der Herr der Ringe und The Hobbit");
+    test!(sentence_acronyms:
+        sentence("the BBC news in NYC") => "The BBC news in NYC");
+    test!(sentence_numerals:
+        sentence("henry viii and i") => "Henry VIII and I");
+    test!(sentence_separators:
+        sentence("MY_SONG  (LIVE)") => "My_song  (live)");
+}
@@ -0,0 +1,251 @@
+//! Ordering entries, e.g. to number them.
+//!
+//! Names are compared naturally, so that the numbers within them are compared
+//! by value and `track2` comes before `track10`. Entries may instead be ordered
+//! by name byte by byte, by when they were modified, changed or taken, or by
+//! size. Ties, and entries whose date or size cannot be read, which come last,
+//! are ordered by name.
+
+use crate::ocd::date::exif_date;
+use chrono::NaiveDate;
+use chrono::NaiveDateTime;
+use clap::ValueEnum;
+use rayon::prelude::*;
+use regex::Regex;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+/// What entries are ordered by.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Order {
+    /// By name, byte by byte.
+    Name,
+    /// By name, comparing the numbers within names by value.
+    #[default]
+    Natural,
+    /// By the time the file was last modified.
+    Mtime,
+    /// By the time the file or its metadata last changed, the Unix ctime. On
+    /// other systems, by the time the file was created.
+    Ctime,
+    /// By the date the photo was taken, from its EXIF data, or else by the
+    /// time the file was last modified.
+    Exif,
+    /// By size.
+    Size,
+}
+
+/// The position of an entry among those in the same directory, from 0, and
+/// the number of entries there.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub(crate) struct Position {
+    pub(crate) index: usize,
+    pub(crate) count: usize,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Time(NaiveDateTime),
+    Size(u64),
+}
+
+impl Order {
+    /// Returns what the entry is ordered by, besides its name.
+    fn key(self, path: &PathBuf) -> Option<Key> {
+        match self {
+            Order::Name | Order::Natural => None,
+            Order::Mtime => modified(path).map(Key::Time),
+            Order::Ctime => changed(path).map(Key::Time),
+            Order::Exif => taken(path).or_else(|| modified(path)).map(Key::Time),
+            Order::Size => std::fs::metadata(path)
+                .ok()
+                .map(|metadata| Key::Size(metadata.len())),
+        }
+    }
+}
+
+/// Returns the paths in the order, reading their dates or sizes in parallel.
+pub(crate) fn sort(paths: Vec<PathBuf>, order: Order) -> Vec<PathBuf> {
+    let mut keyed: Vec<(Option<Key>, PathBuf)> = paths
+        .into_par_iter()
+        .map(|path| (order.key(&path), path))
+        .collect();
+    keyed.sort_by(|(a_key, a), (b_key, b)| {
+        a_key
+            .is_none()
+            .cmp(&b_key.is_none())
+            .then_with(|| a_key.cmp(b_key))
+            .then_with(|| match order {
+                Order::Name => a.cmp(b),
+                _ => natural_cmp(a, b),
+            })
+    });
+    keyed.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Returns the position of each path among those in the same directory.
+pub(crate) fn positions(paths: Vec<PathBuf>, order: Order) -> BTreeMap<PathBuf, Position> {
+    let mut groups: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for path in sort(paths, order) {
+        let parent = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        groups.entry(parent).or_default().push(path);
+    }
+    groups
+        .into_values()
+        .flat_map(|group| {
+            let count = group.len();
+            group
+                .into_iter()
+                .enumerate()
+                .map(move |(index, path)| (path, Position { index, count }))
+        })
+        .collect()
+}
+
+/// Compares paths component by component, comparing the numbers within them
+/// by value and the rest ignoring case, or else byte by byte.
+pub(crate) fn natural_cmp(a: &Path, b: &Path) -> Ordering {
+    let a_names: Vec<_> = a.iter().collect();
+    let b_names: Vec<_> = b.iter().collect();
+    for (a_name, b_name) in a_names.iter().zip(&b_names) {
+        let ordering = compare_names(&a_name.to_string_lossy(), &b_name.to_string_lossy())
+            .then_with(|| a_name.cmp(b_name));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a_names.len().cmp(&b_names.len())
+}
+
+/// Compares names by their runs of digits and of other characters in turn.
+fn compare_names(a: &str, b: &str) -> Ordering {
+    static CHUNK_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"[0-9]+|[^0-9]+").unwrap());
+    let mut a_chunks = CHUNK_REGEX.find_iter(a).map(|chunk| chunk.as_str());
+    let mut b_chunks = CHUNK_REGEX.find_iter(b).map(|chunk| chunk.as_str());
+    loop {
+        let ordering = match (a_chunks.next(), b_chunks.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some(a), Some(b)) if is_number(a) && is_number(b) => {
+                let (a, b) = (a.trim_start_matches('0'), b.trim_start_matches('0'));
+                a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+            }
+            (Some(a), Some(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+fn is_number(chunk: &str) -> bool {
+    chunk.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn modified(path: &Path) -> Option<NaiveDateTime> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(chrono::DateTime::<chrono::Local>::from(modified).naive_local())
+}
+
+#[cfg(unix)]
+fn changed(path: &Path) -> Option<NaiveDateTime> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path).ok()?;
+    let changed = chrono::DateTime::from_timestamp(metadata.ctime(), metadata.ctime_nsec() as u32)?;
+    Some(changed.with_timezone(&chrono::Local).naive_local())
+}
+
+#[cfg(not(unix))]
+fn changed(path: &Path) -> Option<NaiveDateTime> {
+    let created = std::fs::metadata(path).ok()?.created().ok()?;
+    Some(chrono::DateTime::<chrono::Local>::from(created).naive_local())
+}
+
+fn taken(path: &PathBuf) -> Option<NaiveDateTime> {
+    let (_, year, month, day, time) = exif_date(path)?;
+    let time = time?;
+    NaiveDate::from_ymd_opt(year as i32, month, day)?.and_hms_nano_opt(
+        time.hour,
+        time.minute,
+        time.second,
+        time.nanosecond.unwrap_or(0),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn natural_order() {
+        assert_eq!(
+            paths(&["track1.mp3", "Track2.mp3", "track02b.mp3", "track10.mp3"]),
+            sort(
+                paths(&["track10.mp3", "track02b.mp3", "Track2.mp3", "track1.mp3"]),
+                Order::Natural
+            )
+        );
+        assert_eq!(
+            paths(&["Track2.mp3", "track02b.mp3", "track1.mp3", "track10.mp3"]),
+            sort(
+                paths(&["track10.mp3", "track02b.mp3", "Track2.mp3", "track1.mp3"]),
+                Order::Name
+            )
+        );
+        assert_eq!(
+            paths(&["a/2/z", "a/10", "a 1"]),
+            sort(paths(&["a 1", "a/10", "a/2/z"]), Order::Natural)
+        );
+    }
+
+    #[test]
+    fn positions_per_directory() {
+        let positions = positions(
+            paths(&["b/x10", "a/x2", "b/x9", "a/x1", "a/x3"]),
+            Order::Natural,
+        );
+        let position = |path: &str| positions[&PathBuf::from(path)];
+        assert_eq!(Position { index: 0, count: 3 }, position("a/x1"));
+        assert_eq!(Position { index: 2, count: 3 }, position("a/x3"));
+        assert_eq!(Position { index: 0, count: 2 }, position("b/x9"));
+        assert_eq!(Position { index: 1, count: 2 }, position("b/x10"));
+    }
+
+    #[test]
+    fn size_order() {
+        let dir = std::env::temp_dir().join(format!("ocd-order-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a"), b"abc").unwrap();
+        std::fs::write(dir.join("b"), b"a").unwrap();
+        std::fs::write(dir.join("c"), b"ab").unwrap();
+        let sorted = sort(
+            vec![
+                dir.join("a"),
+                dir.join("b"),
+                dir.join("c"),
+                dir.join("missing"),
+            ],
+            Order::Size,
+        );
+        assert_eq!(
+            vec![
+                dir.join("b"),
+                dir.join("c"),
+                dir.join("a"),
+                dir.join("missing")
+            ],
+            sorted
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+//! Rewriting file names to suit the rules of other filesystems.
+//!
+//! A name that is fine on Linux may not be on a Windows share or a USB stick:
+//! Windows and FAT reserve device names such as `CON` or `LPT1`, even with an
+//! extension, forbid characters such as `:` or `?`, and drop trailing periods
+//! and spaces. All filesystems limit the length of a name, counted in UTF-16
+//! units on Windows and FAT and in bytes elsewhere.
+
+use crate::ocd::extension::ExtensionSplitter;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// The longest name allowed, in the units of the filesystem.
+const MAX_NAME_LEN: usize = 255;
+
+/// The character replacing those a filesystem does not allow.
+const REPLACEMENT: char = '_';
+
+/// Names Windows reserves for devices, compared ignoring case and whatever
+/// follows the first period.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// The filesystem whose rules names must follow.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Portability {
+    /// NTFS and Windows shares.
+    Windows,
+    /// FAT32 and exFAT, as on USB sticks and memory cards, which follow the
+    /// Windows rules.
+    Fat,
+    /// Linux, macOS and other Unix filesystems.
+    Posix,
+}
+
+impl Portability {
+    /// Returns the name rewritten to follow the rules: forbidden characters
+    /// replaced, trailing periods and spaces removed, reserved names suffixed
+    /// and long names shortened, keeping the extension.
+    pub(crate) fn rewrite(self, name: &str, splitter: &ExtensionSplitter) -> String {
+        let mut name: String = name
+            .chars()
+            .map(|c| if self.forbids(c) { REPLACEMENT } else { c })
+            .collect();
+        if self != Portability::Posix {
+            name = trim_end(&name);
+            if is_reserved(&name) {
+                let base = name.find('.').unwrap_or(name.len());
+                name.insert(base, REPLACEMENT);
+            }
+        }
+        name = self.truncate(&name, splitter);
+        if self != Portability::Posix {
+            name = trim_end(&name);
+        }
+        name
+    }
+
+    /// Returns why the name does not follow the rules, if it does not.
+    pub(crate) fn violation(self, name: &str) -> Option<String> {
+        if let Some(c) = name.chars().find(|c| self.forbids(*c)) {
+            return Some(format!("contains {c:?}"));
+        }
+        if self != Portability::Posix {
+            if name.ends_with(['.', ' ']) {
+                return Some(String::from("ends with a period or space"));
+            }
+            if is_reserved(name) {
+                return Some(String::from("is a reserved name"));
+            }
+        }
+        if self.len(name) > MAX_NAME_LEN {
+            return Some(format!("is longer than {MAX_NAME_LEN} {}", self.unit()));
+        }
+        None
+    }
+
+    /// Returns true if the name of a file must be valid Unicode.
+    pub(crate) fn requires_unicode(self) -> bool {
+        self != Portability::Posix
+    }
+
+    fn forbids(self, c: char) -> bool {
+        match self {
+            Portability::Windows | Portability::Fat => c < ' ' || "<>:\"/\\|?*".contains(c),
+            Portability::Posix => c == '/' || c == '\0',
+        }
+    }
+
+    /// Returns the length of the name in the units of the filesystem.
+    fn len(self, name: &str) -> usize {
+        match self {
+            Portability::Windows | Portability::Fat => name.encode_utf16().count(),
+            Portability::Posix => name.len(),
+        }
+    }
+
+    fn unit(self) -> &'static str {
+        match self {
+            Portability::Windows | Portability::Fat => "UTF-16 units",
+            Portability::Posix => "bytes",
+        }
+    }
+
+    /// Shortens the stem of the name until the name fits, or the whole name
+    /// if the extension alone does not.
+    fn truncate(self, name: &str, splitter: &ExtensionSplitter) -> String {
+        if self.len(name) <= MAX_NAME_LEN {
+            return String::from(name);
+        }
+        let (stem, extension) = splitter.split(name);
+        let suffix = if extension.is_empty() {
+            String::new()
+        } else {
+            format!(".{extension}")
+        };
+        let (kept, suffix) = if self.len(&suffix) < MAX_NAME_LEN {
+            (stem, suffix.as_str())
+        } else {
+            (name, "")
+        };
+        let mut truncated = String::new();
+        for c in kept.chars() {
+            if self.len(&truncated) + self.len(c.encode_utf8(&mut [0; 4])) + self.len(suffix)
+                > MAX_NAME_LEN
+            {
+                break;
+            }
+            truncated.push(c);
+        }
+        truncated + suffix
+    }
+}
+
+fn trim_end(name: &str) -> String {
+    let trimmed = name.trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() {
+        String::from(REPLACEMENT)
+    } else {
+        String::from(trimmed)
+    }
+}
+
+fn is_reserved(name: &str) -> bool {
+    let base = name.split('.').next().unwrap_or_default().trim_end();
+    RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(base))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rewrite(portability: Portability, name: &str) -> String {
+        portability.rewrite(name, &ExtensionSplitter::default())
+    }
+
+    #[test]
+    fn windows_rewrite() {
+        assert_eq!("CON_.txt", rewrite(Portability::Windows, "CON.txt"));
+        assert_eq!("lpt1_", rewrite(Portability::Windows, "lpt1"));
+        assert_eq!("nul_.tar.gz", rewrite(Portability::Windows, "nul.tar.gz"));
+        assert_eq!("CONSOLE.txt", rewrite(Portability::Windows, "CONSOLE.txt"));
+        assert_eq!("a_b.mp3", rewrite(Portability::Windows, "a:b.mp3"));
+        assert_eq!(
+            "why_ _yes_.txt",
+            rewrite(Portability::Windows, "why? *yes|.txt")
+        );
+        assert_eq!("notes", rewrite(Portability::Windows, "notes. . "));
+        assert_eq!("_", rewrite(Portability::Windows, "..."));
+        assert_eq!("a:b.mp3", rewrite(Portability::Posix, "a:b.mp3"));
+        assert_eq!("CON.txt", rewrite(Portability::Posix, "CON.txt"));
+    }
+
+    #[test]
+    fn truncate_keeps_extension() {
+        let long = format!("{}.flac", "é".repeat(200));
+        let posix = rewrite(Portability::Posix, &long);
+        assert_eq!(format!("{}.flac", "é".repeat(125)), posix);
+        assert_eq!(long, rewrite(Portability::Windows, &long));
+
+        let long = format!("{}.tar.gz", "写".repeat(300));
+        let windows = rewrite(Portability::Windows, &long);
+        assert_eq!(format!("{}.tar.gz", "写".repeat(248)), windows);
+        assert_eq!(None, Portability::Windows.violation(&windows));
+
+        let long = format!("{} .txt", "a".repeat(300));
+        assert_eq!(
+            format!("{}.txt", "a".repeat(251)),
+            rewrite(Portability::Fat, &long)
+        );
+        assert_eq!(
+            "a".repeat(255),
+            rewrite(Portability::Posix, &"a".repeat(300))
+        );
+    }
+
+    #[test]
+    fn violations() {
+        assert_eq!(
+            Some(String::from("is a reserved name")),
+            Portability::Fat.violation("aux.mp3")
+        );
+        assert_eq!(
+            Some(String::from("contains ':'")),
+            Portability::Windows.violation("a:b")
+        );
+        assert_eq!(
+            Some(String::from("ends with a period or space")),
+            Portability::Windows.violation("a.")
+        );
+        assert_eq!(
+            Some(String::from("is longer than 255 bytes")),
+            Portability::Posix.violation(&"é".repeat(128))
+        );
+        assert_eq!(None, Portability::Windows.violation(&"é".repeat(128)));
+        assert_eq!(None, Portability::Posix.violation("a:b."));
+    }
+}
@@ -0,0 +1,86 @@
+//! Progress bars, and paging of long listings.
+//!
+//! Progress is drawn on standard error, and only when it is a terminal, so
+//! that it never ends up in redirected output.
+
+use console::Term;
+use indicatif::MultiProgress;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
+use std::env;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+
+/// The pager used if `PAGER` is not set.
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Returns a spinner counting entries as they are found, or a hidden one if
+/// progress is not shown.
+pub(crate) fn spinner(enabled: bool, message: &'static str) -> ProgressBar {
+    if !enabled {
+        return ProgressBar::hidden();
+    }
+    let spinner = ProgressBar::new_spinner().with_message(message);
+    spinner.set_style(ProgressStyle::with_template("{spinner} {msg}: {pos}").unwrap());
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner
+}
+
+/// Returns a bar counting the steps of a phase, or a hidden one if progress
+/// is not shown.
+pub(crate) fn bar(enabled: bool, len: u64, message: &'static str) -> ProgressBar {
+    if !enabled {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new(len).with_message(message);
+    bar.set_style(
+        ProgressStyle::with_template("{msg:10} [{bar:40}] {pos}/{len} {eta}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    bar
+}
+
+/// Returns a bar counting the bytes copied of a file, added to the bars
+/// already shown.
+pub(crate) fn bytes_bar(bars: &MultiProgress, len: u64, message: String) -> ProgressBar {
+    let bar = ProgressBar::new(len).with_message(message);
+    bar.set_style(
+        ProgressStyle::with_template("{msg} [{bar:40}] {bytes}/{total_bytes} {bytes_per_sec}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    bars.add(bar)
+}
+
+/// Prints the text, through the user's pager if it is enabled and the text
+/// does not fit on the terminal.
+pub(crate) fn page(text: &str, pager: bool) {
+    let term = Term::stdout();
+    let fits = text.lines().count() < usize::from(term.size().0);
+    if !pager || !term.is_term() || fits {
+        print!("{text}");
+        return;
+    }
+    let command = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| String::from(DEFAULT_PAGER));
+    let mut words = command.split_whitespace();
+    let spawned = Command::new(words.next().unwrap())
+        .args(words)
+        .stdin(Stdio::piped())
+        .spawn();
+    match spawned {
+        Ok(mut child) => {
+            if let Some(mut stdin) = child.stdin.take() {
+                // The user may quit the pager before reading everything.
+                let _ = stdin.write_all(text.as_bytes());
+            }
+            let _ = child.wait();
+        }
+        Err(_) => print!("{text}"),
+    }
+}
@@ -0,0 +1,575 @@
+//! Reviewing a plan in the terminal before executing it.
+//!
+//! Every action of the plan is listed with the changed parts of its source and
+//! destination highlighted. Entries can be rejected and accepted one at a
+//! time, their destinations edited, and the list filtered. For commands
+//! driven by a program, such as mrn, the program can be edited and the plan is
+//! recomputed as it is typed. Only the accepted entries are executed.
+
+use crate::ocd::diff;
+use crate::ocd::diff::Change;
+use crate::ocd::display_path;
+use crate::ocd::Plan;
+use ratatui::crossterm::event;
+use ratatui::crossterm::event::Event;
+use ratatui::crossterm::event::KeyCode;
+use ratatui::crossterm::event::KeyEvent;
+use ratatui::crossterm::event::KeyEventKind;
+use ratatui::crossterm::event::KeyModifiers;
+use ratatui::layout::Constraint;
+use ratatui::layout::Layout;
+use ratatui::style::Color;
+use ratatui::style::Modifier;
+use ratatui::style::Style;
+use ratatui::text::Line;
+use ratatui::text::Span;
+use ratatui::widgets::List;
+use ratatui::widgets::ListItem;
+use ratatui::widgets::ListState;
+use ratatui::widgets::Paragraph;
+use ratatui::DefaultTerminal;
+use ratatui::Frame;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How long typing must pause before the edited program is run again.
+const REPLAN_DELAY: Duration = Duration::from_millis(150);
+
+/// The number of entries skipped by page up and page down.
+const PAGE: usize = 10;
+
+/// Computes a new plan from an edited program.
+pub(crate) type Replan<'a> = Box<dyn FnMut(&str) -> Result<Plan, Box<dyn Error>> + 'a>;
+
+/// Lets the user review the plan, and returns the accepted part of it, or
+/// nothing if the user quits. The program the plan was computed from may be
+/// given along with a way to compute the plan again once it is edited.
+pub(crate) fn review<'a>(
+    plan: Plan,
+    program: Option<(String, Replan<'a>)>,
+) -> Result<Option<Plan>, Box<dyn Error>> {
+    let mut review = Review::new(plan, program);
+    let mut terminal = ratatui::try_init()?;
+    let result = review.run(&mut terminal);
+    ratatui::try_restore()?;
+    result
+}
+
+/// What the keys typed currently do.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mode {
+    Browse,
+    Edit,
+    Filter,
+    Program,
+}
+
+enum Outcome {
+    Continue,
+    Accept(Plan),
+    Quit,
+}
+
+struct Review<'a> {
+    plan: Plan,
+    /// The sources of the entries the user rejected.
+    rejected: HashSet<PathBuf>,
+    /// The destinations the user edited, kept when the plan is recomputed.
+    edited: BTreeMap<PathBuf, PathBuf>,
+    filter: String,
+    program: Option<String>,
+    replan: Option<Replan<'a>>,
+    /// Set when the program was edited and the plan not yet recomputed.
+    stale: bool,
+    mode: Mode,
+    /// The text being typed, and the value it replaces if cancelled.
+    input: String,
+    saved: String,
+    list: ListState,
+    message: Option<String>,
+}
+
+impl<'a> Review<'a> {
+    fn new(plan: Plan, program: Option<(String, Replan<'a>)>) -> Self {
+        let (program, replan) = match program {
+            Some((program, replan)) => (Some(program), Some(replan)),
+            None => (None, None),
+        };
+        Review {
+            plan,
+            rejected: HashSet::new(),
+            edited: BTreeMap::new(),
+            filter: String::new(),
+            program,
+            replan,
+            stale: false,
+            mode: Mode::Browse,
+            input: String::new(),
+            saved: String::new(),
+            list: ListState::default().with_selected(Some(0)),
+            message: None,
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<Option<Plan>, Box<dyn Error>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if self.stale && !event::poll(REPLAN_DELAY)? {
+                self.replan();
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                match self.handle(key) {
+                    Outcome::Continue => {}
+                    Outcome::Accept(plan) => return Ok(Some(plan)),
+                    Outcome::Quit => return Ok(None),
+                }
+            }
+        }
+    }
+
+    /// The sources of the entries matching the filter, in order.
+    fn visible(&self) -> Vec<&PathBuf> {
+        self.plan
+            .listed()
+            .into_iter()
+            .map(|(src, _)| src)
+            .filter(|src| matches(&self.filter, src, &self.destination(src)))
+            .collect()
+    }
+
+    /// Where the entry ends up once the plan is executed.
+    fn destination(&self, src: &Path) -> PathBuf {
+        match self.plan.actions.get(src) {
+            Some(action) => self.plan.final_path(&action.destination(src)),
+            None => src.to_path_buf(),
+        }
+    }
+
+    fn selected(&self) -> Option<PathBuf> {
+        let index = self.list.selected()?;
+        self.visible().get(index).map(|src| src.to_path_buf())
+    }
+
+    /// Keeps the selection within the visible entries.
+    fn clamp(&mut self) {
+        let len = self.visible().len();
+        let index = self.list.selected().unwrap_or(0);
+        self.list.select(Some(index.min(len.saturating_sub(1))));
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let last = self.visible().len().saturating_sub(1);
+        let index = self.list.selected().unwrap_or(0);
+        let index = index.saturating_add_signed(delta).min(last);
+        self.list.select(Some(index));
+    }
+
+    fn handle(&mut self, key: KeyEvent) -> Outcome {
+        if key.kind != KeyEventKind::Press {
+            return Outcome::Continue;
+        }
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Outcome::Quit;
+        }
+        if self.mode == Mode::Browse {
+            return self.browse(key.code);
+        }
+        match key.code {
+            KeyCode::Enter => self.commit(),
+            KeyCode::Esc => self.cancel(),
+            KeyCode::Backspace => {
+                self.input.pop();
+                self.typed();
+            }
+            KeyCode::Char(c) => {
+                self.input.push(c);
+                self.typed();
+            }
+            _ => {}
+        }
+        Outcome::Continue
+    }
+
+    fn browse(&mut self, code: KeyCode) -> Outcome {
+        self.message = None;
+        match code {
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::PageUp => self.move_selection(-(PAGE as isize)),
+            KeyCode::PageDown => self.move_selection(PAGE as isize),
+            KeyCode::Home | KeyCode::Char('g') => self.list.select(Some(0)),
+            KeyCode::End | KeyCode::Char('G') => self.move_selection(isize::MAX),
+            KeyCode::Char(' ') => {
+                if let Some(src) = self.selected() {
+                    if !self.rejected.remove(&src) {
+                        self.rejected.insert(src);
+                    }
+                }
+                self.move_selection(1);
+            }
+            KeyCode::Char('a') => {
+                let visible: Vec<PathBuf> = self.visible().into_iter().cloned().collect();
+                for src in visible {
+                    self.rejected.remove(&src);
+                }
+            }
+            KeyCode::Char('n') => {
+                let visible: Vec<PathBuf> = self.visible().into_iter().cloned().collect();
+                self.rejected.extend(visible);
+            }
+            KeyCode::Char('e') => {
+                if let Some(src) = self.selected() {
+                    let path = self.plan.actions[&src].path();
+                    self.start(Mode::Edit, path.to_string_lossy().into_owned());
+                }
+            }
+            KeyCode::Char('/') => self.start(Mode::Filter, self.filter.clone()),
+            KeyCode::Char('p') => match &self.program {
+                Some(program) => self.start(Mode::Program, program.clone()),
+                None => self.message = Some(String::from("There is no program to edit.")),
+            },
+            KeyCode::Enter => return self.accept(),
+            KeyCode::Char('q') | KeyCode::Esc => return Outcome::Quit,
+            _ => {}
+        }
+        Outcome::Continue
+    }
+
+    fn start(&mut self, mode: Mode, input: String) {
+        self.mode = mode;
+        self.saved = input.clone();
+        self.input = input;
+    }
+
+    /// Applies the text typed so far, to filter or replan as it is typed.
+    fn typed(&mut self) {
+        match self.mode {
+            Mode::Filter => {
+                self.filter = self.input.clone();
+                self.clamp();
+            }
+            Mode::Program => {
+                self.program = Some(self.input.clone());
+                self.stale = true;
+            }
+            Mode::Browse | Mode::Edit => {}
+        }
+    }
+
+    fn commit(&mut self) {
+        if self.mode == Mode::Edit && !self.input.trim().is_empty() {
+            if let Some(src) = self.selected() {
+                let path = PathBuf::from(&self.input);
+                if let Some(action) = self.plan.actions.get_mut(&src) {
+                    *action.path_mut() = path.clone();
+                }
+                self.edited.insert(src, path);
+            }
+        }
+        if self.stale {
+            self.replan();
+        }
+        self.mode = Mode::Browse;
+    }
+
+    fn cancel(&mut self) {
+        self.input = self.saved.clone();
+        self.typed();
+        if self.stale {
+            self.replan();
+        }
+        self.mode = Mode::Browse;
+    }
+
+    /// Computes the plan again from the edited program, keeping the edited
+    /// destinations. The current plan is kept if the program has errors.
+    fn replan(&mut self) {
+        self.stale = false;
+        let (Some(program), Some(replan)) = (&self.program, &mut self.replan) else {
+            return;
+        };
+        match replan(program) {
+            Ok(mut plan) => {
+                for (src, path) in &self.edited {
+                    if let Some(action) = plan.actions.get_mut(src) {
+                        *action.path_mut() = path.clone();
+                    }
+                }
+                self.plan = plan;
+                self.message = None;
+            }
+            Err(err) => self.message = Some(err.to_string()),
+        }
+        self.clamp();
+    }
+
+    /// Returns the plan of the accepted entries, unless they collide.
+    fn accept(&mut self) -> Outcome {
+        let mut plan = self.plan.clone();
+        plan.retain(|src| !self.rejected.contains(src));
+        match plan.check_collisions() {
+            Ok(()) => Outcome::Accept(plan),
+            Err(err) => {
+                self.message = Some(err.to_string());
+                Outcome::Continue
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let program_height = u16::from(self.program.is_some());
+        let [header, program, list, status, help] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(program_height),
+            Constraint::Min(1),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let total = self.plan.actions.len();
+        let accepted = self
+            .plan
+            .actions
+            .keys()
+            .filter(|src| !self.rejected.contains(*src))
+            .count();
+        let mut title = format!("Reviewing {total} entries, {accepted} accepted");
+        if !self.filter.is_empty() {
+            title.push_str(&format!(", filtered by '{}'", self.filter));
+        }
+        frame.render_widget(
+            Paragraph::new(title).style(Style::new().add_modifier(Modifier::BOLD)),
+            header,
+        );
+        if let Some(text) = &self.program {
+            let line = if self.mode == Mode::Program {
+                format!("Program: {}_", self.input)
+            } else {
+                format!("Program: {text}")
+            };
+            frame.render_widget(Paragraph::new(line), program);
+        }
+
+        let items: Vec<ListItem> = self
+            .visible()
+            .into_iter()
+            .map(|src| self.item(src))
+            .collect();
+        let entries =
+            List::new(items).highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(entries, list, &mut self.list);
+
+        let line = match self.mode {
+            Mode::Browse => match &self.message {
+                Some(message) => Line::styled(message.as_str(), Style::new().fg(Color::Red)),
+                None => Line::default(),
+            },
+            Mode::Edit => Line::raw(format!("Destination: {}_", self.input)),
+            Mode::Filter => Line::raw(format!("Filter: {}_", self.input)),
+            Mode::Program => match &self.message {
+                Some(message) => Line::styled(message.as_str(), Style::new().fg(Color::Red)),
+                None => Line::raw("Program: editing, the plan follows as you type"),
+            },
+        };
+        frame.render_widget(Paragraph::new(line), status);
+        let keys = match self.mode {
+            Mode::Browse => {
+                "space toggle  a accept all  n reject all  e edit  / filter  p program  enter execute  q quit"
+            }
+            Mode::Edit | Mode::Filter | Mode::Program => "enter confirm  esc cancel",
+        };
+        frame.render_widget(
+            Paragraph::new(keys).style(Style::new().fg(Color::DarkGray)),
+            help,
+        );
+    }
+
+    /// Renders an entry, its changes highlighted unless it is rejected.
+    fn item(&self, src: &Path) -> ListItem<'static> {
+        let before = display_path(src).into_owned();
+        let after = display_path(&self.destination(src)).into_owned();
+        if self.rejected.contains(src) {
+            let line = format!("[ ] {before} -> {after}");
+            return ListItem::new(Line::styled(line, Style::new().fg(Color::DarkGray)));
+        }
+        let changes = diff::changes(&before, &after);
+        let mut line = vec![Span::raw("[x] ")];
+        line.extend(spans(&changes, true));
+        line.push(Span::raw(" -> "));
+        line.extend(spans(&changes, false));
+        ListItem::new(Line::from(line))
+    }
+}
+
+/// Renders the old side of the changes, with the characters removed in red,
+/// or the new side, with those inserted in green.
+fn spans(changes: &[Change], old: bool) -> Vec<Span<'static>> {
+    changes
+        .iter()
+        .filter_map(|change| match (change, old) {
+            (Change::Same(text), _) => Some(Span::raw(text.clone())),
+            (Change::Removed(text), true) => {
+                Some(Span::styled(text.clone(), Style::new().fg(Color::Red)))
+            }
+            (Change::Inserted(text), false) => {
+                Some(Span::styled(text.clone(), Style::new().fg(Color::Green)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns true if the source or the destination contains the filter,
+/// ignoring case.
+fn matches(filter: &str, src: &Path, dst: &Path) -> bool {
+    let filter = filter.to_lowercase();
+    [src, dst]
+        .iter()
+        .any(|path| display_path(path).to_lowercase().contains(&filter))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ocd::Action;
+
+    fn plan() -> Plan {
+        let mut plan = Plan::new();
+        for (src, dst) in [
+            ("a/One.JPG", "a/one.jpg"),
+            ("a/Two.JPG", "a/two.jpg"),
+            ("b/Three.JPG", "b/three.jpg"),
+        ] {
+            let path = PathBuf::from(dst);
+            plan.insert(PathBuf::from(src), Action::Rename { path });
+        }
+        plan
+    }
+
+    fn press(review: &mut Review, codes: &[KeyCode]) -> Option<Outcome> {
+        let mut outcome = None;
+        for code in codes {
+            outcome = Some(review.handle(KeyEvent::from(*code)));
+        }
+        outcome
+    }
+
+    #[test]
+    fn spans_test() {
+        let changes = diff::changes("IMG_001.jpg", "IMG_2024_001.jpg");
+        let text = |spans: Vec<Span>| -> Vec<String> {
+            spans.iter().map(|span| span.content.to_string()).collect()
+        };
+        assert_eq!(vec!["IMG_", "001.jpg"], text(spans(&changes, true)));
+        assert_eq!(
+            vec!["IMG_", "2024_", "001.jpg"],
+            text(spans(&changes, false))
+        );
+        assert_eq!(Some(Color::Green), spans(&changes, false)[1].style.fg);
+    }
+
+    #[test]
+    fn matches_test() {
+        let (src, dst) = (Path::new("a/One.JPG"), Path::new("a/one.jpg"));
+        assert!(matches("", src, dst));
+        assert!(matches("one", src, dst));
+        assert!(matches("ONE.jpg", src, dst));
+        assert!(!matches("two", src, dst));
+    }
+
+    #[test]
+    fn toggle_and_accept() {
+        let mut review = Review::new(plan(), None);
+        press(&mut review, &[KeyCode::Char(' ')]);
+        assert!(review.rejected.contains(Path::new("a/One.JPG")));
+        assert_eq!(Some(1), review.list.selected());
+        let outcome = press(&mut review, &[KeyCode::Enter]);
+        match outcome {
+            Some(Outcome::Accept(plan)) => assert_eq!(
+                vec![Path::new("a/Two.JPG"), Path::new("b/Three.JPG")],
+                plan.actions.keys().collect::<Vec<&PathBuf>>()
+            ),
+            _ => panic!("The plan was not accepted."),
+        }
+    }
+
+    #[test]
+    fn filter_and_reject_visible() {
+        let mut review = Review::new(plan(), None);
+        press(
+            &mut review,
+            &[KeyCode::Char('/'), KeyCode::Char('b'), KeyCode::Char('/')],
+        );
+        assert_eq!(vec![Path::new("b/Three.JPG")], review.visible());
+        press(&mut review, &[KeyCode::Enter, KeyCode::Char('n')]);
+        assert_eq!(
+            HashSet::from([PathBuf::from("b/Three.JPG")]),
+            review.rejected
+        );
+        press(&mut review, &[KeyCode::Char('/'), KeyCode::Esc]);
+        assert_eq!("b/", review.filter);
+    }
+
+    #[test]
+    fn edit_destination() {
+        let mut review = Review::new(plan(), None);
+        press(&mut review, &[KeyCode::Down, KeyCode::Char('e')]);
+        assert_eq!("a/two.jpg", review.input);
+        let mut codes = vec![KeyCode::Backspace; 7];
+        codes.extend("2.jpg".chars().map(KeyCode::Char));
+        codes.push(KeyCode::Enter);
+        press(&mut review, &codes);
+        assert_eq!(
+            PathBuf::from("a/2.jpg"),
+            review.destination(Path::new("a/Two.JPG"))
+        );
+        assert_eq!(Mode::Browse, review.mode);
+    }
+
+    #[test]
+    fn edited_program_replans() {
+        let replan: Replan = Box::new(|program| {
+            if program.is_empty() {
+                return Err("No program.".into());
+            }
+            let mut plan = plan();
+            for action in plan.actions.values_mut() {
+                let path = action.path().with_extension(program);
+                *action.path_mut() = path;
+            }
+            Ok(plan)
+        });
+        let mut review = Review::new(plan(), Some((String::from("jpg"), replan)));
+        press(
+            &mut review,
+            &[KeyCode::Char('e'), KeyCode::Char('x'), KeyCode::Enter],
+        );
+        press(&mut review, &[KeyCode::Char('p'), KeyCode::Backspace]);
+        assert!(review.stale);
+        press(&mut review, &[KeyCode::Char('e'), KeyCode::Enter]);
+        assert!(!review.stale);
+        assert_eq!(Some("jpe"), review.program.as_deref());
+        assert_eq!(
+            PathBuf::from("a/one.jpgx"),
+            review.destination(Path::new("a/One.JPG"))
+        );
+        assert_eq!(
+            PathBuf::from("a/two.jpe"),
+            review.destination(Path::new("a/Two.JPG"))
+        );
+        press(&mut review, &[KeyCode::Char('p')]);
+        press(&mut review, &[KeyCode::Backspace; 3]);
+        press(&mut review, &[KeyCode::Enter]);
+        assert_eq!(Some("No program."), review.message.as_deref());
+        assert_eq!(
+            PathBuf::from("a/two.jpe"),
+            review.destination(Path::new("a/Two.JPG"))
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Moving, copying and linking files, possibly between filesystems.
+//!
+//! `fs::rename` cannot move an entry to another filesystem, e.g. from an SD
+//! card to a NAS. In that case a file is copied to a temporary name next to
+//! the destination, flushed to disk, optionally verified against a checksum of
+//! the source, renamed into place, and only then is the source deleted.
+//! Permissions and access and modification times are preserved.
+
+use crate::ocd::progress;
+use indicatif::MultiProgress;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::fs::File;
+use std::fs::FileTimes;
+use std::hash::Hasher;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+
+/// Files at least this large report the progress of their copy.
+const PROGRESS_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// How entries are moved when they cannot simply be renamed.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Transfer {
+    verify: bool,
+    progress: bool,
+    bars: MultiProgress,
+}
+
+impl Transfer {
+    pub(crate) fn new() -> Self {
+        Transfer::default()
+    }
+
+    /// Reads back each copy and compares its checksum with the source's.
+    pub(crate) fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Reports the progress of copying large files.
+    pub(crate) fn with_progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// The progress bars shown, to which others may be added.
+    pub(crate) fn bars(&self) -> &MultiProgress {
+        &self.bars
+    }
+
+    /// Renames the source to the destination, copying it and deleting the
+    /// source if they are on different filesystems.
+    pub(crate) fn rename(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        match fs::rename(src, dst) {
+            Err(err) if err.kind() == io::ErrorKind::CrossesDevices => self.move_across(src, dst),
+            result => result,
+        }
+    }
+
+    fn move_across(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(src)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::CrossesDevices,
+                format!("Cannot move {} to another filesystem.", src.display()),
+            ));
+        }
+        self.copy(src, dst)?;
+        fs::remove_file(src)
+    }
+
+    /// Copies the file through a temporary name, so that an interrupted copy
+    /// never leaves a partial file at the destination.
+    pub(crate) fn copy(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let metadata = fs::metadata(src)?;
+        let partial = crate::ocd::case::temporary_path(dst);
+        match self.copy_contents(src, &partial, &metadata) {
+            Ok(()) => {}
+            Err(err) => {
+                let _ = fs::remove_file(&partial);
+                return Err(err);
+            }
+        }
+        fs::rename(&partial, dst)?;
+        sync_parent(dst)
+    }
+
+    /// Copies the file, flushes it to disk and verifies it if required.
+    fn copy_contents(&self, src: &Path, dst: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+        let mut reader = File::open(src)?;
+        let mut writer = File::create(dst)?;
+        let total = metadata.len();
+        let bar = (self.progress && total >= PROGRESS_THRESHOLD)
+            .then(|| progress::bytes_bar(&self.bars, total, src.display().to_string()));
+        let mut hasher = DefaultHasher::new();
+        let mut buffer = vec![0; BUFFER_SIZE];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..read])?;
+            hasher.write(&buffer[..read]);
+            if let Some(bar) = &bar {
+                bar.inc(read as u64);
+            }
+        }
+        if let Some(bar) = bar {
+            bar.finish_and_clear();
+        }
+        writer.set_permissions(metadata.permissions())?;
+        writer.set_times(
+            FileTimes::new()
+                .set_accessed(metadata.accessed()?)
+                .set_modified(metadata.modified()?),
+        )?;
+        writer.sync_all()?;
+        if self.verify && checksum(dst)? != hasher.finish() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("The copy of {} does not match the original.", src.display()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Creates a symbolic link to the source, by its absolute path so that the
+/// link works from any directory.
+#[cfg(unix)]
+pub(crate) fn symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::canonicalize(src)?, dst)
+}
+
+#[cfg(windows)]
+pub(crate) fn symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(fs::canonicalize(src)?, dst)
+}
+
+/// Creates a copy sharing the source's data until either is modified, on
+/// filesystems that support it, such as Btrfs, XFS and APFS.
+pub(crate) fn reflink(src: &Path, dst: &Path) -> io::Result<()> {
+    let args: &[&str] = if cfg!(target_os = "macos") {
+        &["-c", "-p"]
+    } else {
+        &["--reflink=always", "--preserve=mode,timestamps"]
+    };
+    let output = Command::new("cp").args(args).arg(src).arg(dst).output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        // cp may leave an empty file behind.
+        let _ = fs::remove_file(dst);
+        Err(io::Error::other(format!(
+            "Cannot reflink {}: {}",
+            src.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+fn checksum(path: &Path) -> io::Result<u64> {
+    let mut reader = File::open(path)?;
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&buffer[..read]);
+    }
+}
+
+/// Flushes the directory entry of a renamed file to disk.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn copy_preserves_contents_and_times() {
+        let dir = env::temp_dir().join(format!("ocd-transfer-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("src.jpg");
+        let dst = dir.join("dst.jpg");
+        fs::write(&src, b"not really an image").unwrap();
+        let metadata = fs::metadata(&src).unwrap();
+        Transfer::new().with_verify(true).copy(&src, &dst).unwrap();
+        assert_eq!(fs::read(&src).unwrap(), fs::read(&dst).unwrap());
+        assert_eq!(
+            metadata.modified().unwrap(),
+            fs::metadata(&dst).unwrap().modified().unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
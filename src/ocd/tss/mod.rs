@@ -2,20 +2,34 @@
 //!
 //! This command sorts image files into folders named after a date extracted from the image.
 
+use crate::ocd::config::is_unset;
+use crate::ocd::config::TimeStampSortConfig;
 use crate::ocd::date::exif_date;
 use crate::ocd::date::filename_date;
 use crate::ocd::date::metadata_date;
 use crate::ocd::date::DateSource;
+use crate::ocd::diff;
+use crate::ocd::order::Order;
+use crate::ocd::progress;
+use crate::ocd::review;
+use crate::ocd::transfer::Transfer;
+use crate::ocd::walk::WalkArgs;
+use crate::ocd::walk::Walker;
 use crate::ocd::Action;
+use crate::ocd::Confirmation;
+use crate::ocd::Mode;
 use crate::ocd::Plan;
 use crate::ocd::Speaker;
 use crate::ocd::Verbosity;
+use clap::ArgMatches;
 use clap::Args;
+use clap::ColorChoice;
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::Deserialize;
 use std::error::Error;
 use std::path::Path;
 use std::path::PathBuf;
-use walkdir::DirEntry;
-use walkdir::WalkDir;
 
 /// Arguments to the time stamp sort command.
 #[derive(Clone, Debug, Args)]
@@ -37,6 +51,25 @@ Default is low, one medium, two high, three or more debug."#)]
     #[arg(short = 'd')]
     dir: PathBuf,
 
+    #[arg(
+        help = r#"Sort into date directories under this directory, which may be
+on another filesystem. Defaults to --dir."#
+    )]
+    #[arg(long)]
+    #[arg(value_name = "DIR")]
+    dest: Option<PathBuf>,
+
+    #[arg(help = r#"When moving files to another filesystem, read back each copy
+and compare its checksum with the original's before deleting the original."#)]
+    #[arg(long)]
+    #[arg(overrides_with = "no_verify")]
+    verify: bool,
+
+    #[arg(help = "Do not verify copies, even if the configuration file says so.")]
+    #[arg(long)]
+    #[arg(overrides_with = "verify")]
+    no_verify: bool,
+
     #[arg(help = "Do not effect any changes on the filesystem.")]
     #[arg(long = "dry-run")]
     dry_run: bool,
@@ -44,24 +77,205 @@ Default is low, one medium, two high, three or more debug."#)]
     #[arg(help = "Create undo script.")]
     #[arg(long)]
     #[arg(short = 'u')]
+    #[arg(overrides_with = "no_undo")]
     undo: bool,
 
+    #[arg(help = "Do not create an undo script, even if the configuration file says so.")]
+    #[arg(long)]
+    #[arg(overrides_with = "undo")]
+    no_undo: bool,
+
     #[arg(help = "Do not ask for confirmation.")]
     #[arg(long)]
     yes: bool,
 
-    #[arg(help = "Rename files by calling `git mv`.")]
+    #[arg(help = r#"Review the plan in a terminal interface before executing it,
+accepting, rejecting or editing the destination of each file. Only the
+accepted files are sorted."#)]
+    #[arg(long)]
+    #[arg(conflicts_with = "yes")]
+    review: bool,
+
+    #[arg(default_value = "all")]
+    #[arg(
+        help = r#"Specifies whether the whole plan is confirmed at once, or each
+file in turn, answering y(es), n(o), a(ll remaining), q(uit, skipping the
+remaining ones) or e(dit the destination)."#
+    )]
     #[arg(long)]
+    #[arg(value_name = "WHEN")]
+    #[arg(conflicts_with_all = ["yes", "review"])]
+    confirm: Confirmation,
+
+    #[arg(
+        help = r#"Record the renames of tracked files in git, as `git mv` does.
+Untracked files are simply renamed."#
+    )]
+    #[arg(long)]
+    #[arg(overrides_with = "no_git")]
     git: bool,
 
+    #[arg(help = "Do not record renames in git, even if the configuration file says so.")]
+    #[arg(long)]
+    #[arg(overrides_with = "git")]
+    no_git: bool,
+
     #[arg(help = "Recurse directories.")]
     #[arg(long)]
     #[arg(short = 'r')]
+    #[arg(overrides_with = "no_recurse")]
     recurse: bool,
 
+    #[arg(help = "Do not recurse directories, even if the configuration file says so.")]
+    #[arg(long)]
+    #[arg(overrides_with = "recurse")]
+    no_recurse: bool,
+
     #[arg(help = "Restricts sources for inferring the image date.")]
     #[arg(long)]
     source: bool,
+
+    #[arg(default_value = "day")]
+    #[arg(
+        help = r#"Specifies whether files are sorted into one directory per day,
+or one directory per hour when the date includes a time."#
+    )]
+    #[arg(long)]
+    #[arg(short = 'b')]
+    bucket: Bucket,
+
+    #[arg(default_value = "move")]
+    #[arg(help = r#"Specifies whether files are moved into the date directories,
+or copied or linked there, leaving the originals in place."#)]
+    #[arg(long)]
+    action: SortAction,
+
+    #[arg(
+        help = r#"The order files are listed in. By default files are listed by name.
+Files keep their names, they are not numbered."#
+    )]
+    #[arg(long = "sort-by")]
+    #[arg(value_name = "ORDER")]
+    #[arg(overrides_with = "no_sort_by")]
+    sort_by: Option<Order>,
+
+    #[arg(help = "List files by name, even if the configuration file sets an order.")]
+    #[arg(long = "no-sort-by")]
+    #[arg(overrides_with = "sort_by")]
+    no_sort_by: bool,
+
+    #[arg(default_value = "0")]
+    #[arg(help = r#"Number of threads reading dates from file names, EXIF data
+and metadata while planning, or 0 for one per CPU. Files are always moved one
+at a time."#)]
+    #[arg(long)]
+    #[arg(short = 'j')]
+    #[arg(value_name = "N")]
+    jobs: usize,
+
+    #[arg(help = r#"Show the number of files for each action, date source and
+destination directory instead of listing every file."#)]
+    #[arg(long)]
+    summary: bool,
+
+    #[arg(help = "Print long listings directly instead of through the pager.")]
+    #[arg(long = "no-pager")]
+    no_pager: bool,
+
+    #[arg(default_value = "auto")]
+    #[arg(
+        help = r#"Specifies whether the changes to each name are highlighted in
+listings: if standard output is a terminal, always, or never."#
+    )]
+    #[arg(long)]
+    #[arg(value_name = "WHEN")]
+    color: ColorChoice,
+
+    #[command(flatten)]
+    walk: WalkArgs,
+}
+
+/// How files are placed into the date directories.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortAction {
+    Move,
+    Copy,
+    Hardlink,
+    Symlink,
+    Reflink,
+}
+
+/// The span of time covered by each destination directory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Bucket {
+    Day,
+    Hour,
+}
+
+impl TimeStampSortArgs {
+    /// Fills in the arguments not given on the command line with the user's
+    /// configured defaults.
+    pub(crate) fn with_config(
+        mut self,
+        config: &TimeStampSortConfig,
+        matches: &ArgMatches,
+    ) -> Self {
+        if let Some(verbosity) = config.verbosity.filter(|_| is_unset(matches, "verbosity")) {
+            self.verbosity = verbosity;
+        }
+        if let Some(git) = config
+            .git
+            .filter(|_| is_unset(matches, "git") && !self.no_git)
+        {
+            self.git = git;
+        }
+        if let Some(undo) = config
+            .undo
+            .filter(|_| is_unset(matches, "undo") && !self.no_undo)
+        {
+            self.undo = undo;
+        }
+        if let Some(recurse) = config
+            .recurse
+            .filter(|_| is_unset(matches, "recurse") && !self.no_recurse)
+        {
+            self.recurse = recurse;
+        }
+        if let Some(verify) = config
+            .verify
+            .filter(|_| is_unset(matches, "verify") && !self.no_verify)
+        {
+            self.verify = verify;
+        }
+        if self.dest.is_none() {
+            self.dest.clone_from(&config.dest);
+        }
+        if let Some(bucket) = config.bucket.filter(|_| is_unset(matches, "bucket")) {
+            self.bucket = bucket;
+        }
+        if let Some(action) = config.action.filter(|_| is_unset(matches, "action")) {
+            self.action = action;
+        }
+        if let Some(jobs) = config.jobs.filter(|_| is_unset(matches, "jobs")) {
+            self.jobs = jobs;
+        }
+        if let Some(confirm) = config.confirm.filter(|_| is_unset(matches, "confirm")) {
+            self.confirm = confirm;
+        }
+        if self.sort_by.is_none() && !self.no_sort_by {
+            self.sort_by = config.sort_by;
+        }
+        self
+    }
+
+    /// Returns true if the user confirms each action in turn, which is not the
+    /// case if the plan is executed without confirmation, reviewed, or not
+    /// executed at all.
+    fn confirms_each(&self) -> bool {
+        self.confirm == Confirmation::Each && !self.yes && !self.review && !self.dry_run
+    }
 }
 
 impl Speaker for TimeStampSortArgs {
@@ -74,19 +288,42 @@ pub fn run(config: &TimeStampSortArgs) -> Result<(), Box<dyn Error>> {
     if config.source {
         todo!("Selection of date source is not implemented yet!");
     }
+    diff::set_color(config.color);
 
     // Initialize plan
-    let plan = create_plan(config)?;
+    let mut plan = create_plan(config)?;
+    if let Some(order) = config.sort_by {
+        plan.sort_by(order, config.jobs)?;
+    }
 
     // Present plan to user.
     // If verbosity is Low or Medium use the short presentation.
     // If verbosity is High or Debug use the long presentation.
-    if Verbosity::Silent < config.verbosity() && config.verbosity() < Verbosity::High {
-        plan.present_short();
-    }
-    if Verbosity::Medium < config.verbosity() {
-        plan.present_long();
-    }
+    let pager = !config.no_pager;
+    let plan = if config.review {
+        match review::review(plan, None)? {
+            Some(plan) => plan,
+            None => return Ok(()),
+        }
+    } else {
+        if config.summary && !config.verbosity().is_silent() {
+            plan.present_summary(pager);
+        } else {
+            if Verbosity::Silent < config.verbosity() && config.verbosity() < Verbosity::High {
+                plan.present_short(pager);
+            }
+            if Verbosity::Medium < config.verbosity() {
+                plan.present_long(pager);
+            }
+        }
+        plan
+    };
+    let plan = if config.confirms_each() {
+        plan.confirm_each()
+    } else {
+        plan
+    };
+    plan.check_collisions()?;
 
     // Maybe create undo script
     if !config.dry_run && config.undo {
@@ -97,7 +334,9 @@ pub fn run(config: &TimeStampSortArgs) -> Result<(), Box<dyn Error>> {
     }
 
     // Skip if dry run, execute unconditionally or ask for confirmation
-    if !config.dry_run && (config.yes || crate::ocd::user_confirm()) {
+    if !config.dry_run
+        && (config.yes || config.review || config.confirms_each() || crate::ocd::user_confirm())
+    {
         plan.execute()?;
     }
     Ok(())
@@ -143,17 +382,62 @@ fn create_plan(config: &TimeStampSortArgs) -> Result<Plan, Box<dyn Error>> {
     //     })?;
 
     // version 5
-    let mut plan = Plan::new();
-    let max_depth = if config.recurse { usize::MAX } else { 1 };
-    WalkDir::new(&config.dir)
-        .max_depth(max_depth)
-        .sort_by_file_name()
-        .into_iter()
-        .try_for_each(|entry| {
-            entry.map(|entry| {
-                maybe_insert(config, &mut plan, entry);
+    // let mut plan = Plan::new();
+    // let max_depth = if config.recurse { usize::MAX } else { 1 };
+    // WalkDir::new(&config.dir)
+    //     .max_depth(max_depth)
+    //     .sort_by_file_name()
+    //     .into_iter()
+    //     .try_for_each(|entry| {
+    //         entry.map(|entry| {
+    //             maybe_insert(config, &mut plan, entry);
+    //         })
+    //     })?;
+
+    // version 6
+    // Walker::new(&config.dir, &config.walk)
+    //     .with_mode(Mode::Files)
+    //     .with_recurse(config.recurse)
+    //     .with_git(config.git)
+    //     .entries()?
+    //     .into_iter()
+    //     .for_each(|entry| maybe_insert(config, &mut plan, entry));
+
+    // version 7
+    let transfer = Transfer::new()
+        .with_verify(config.verify)
+        .with_progress(!config.verbosity().is_silent());
+    let progress = config.verbosity().shows_progress();
+    let mut plan = Plan::new()
+        .with_git(config.git)
+        .with_transfer(transfer)
+        .with_progress(progress);
+    let entries = Walker::new(&config.dir, &config.walk)
+        .with_mode(Mode::Files)
+        .with_recurse(config.recurse)
+        .with_git(config.git)
+        .with_progress(progress)
+        .entries()?;
+    let bar = progress::bar(progress, entries.len() as u64, "Planning");
+    // Reading dates is done in parallel, and the results are inserted into
+    // the plan in the order of the entries.
+    let destinations: Vec<_> = crate::ocd::with_jobs(config.jobs, || {
+        entries
+            .par_iter()
+            .map(|entry| {
+                bar.inc(1);
+                is_image(entry)
+                    .then(|| destination(config, entry))
+                    .flatten()
             })
-        })?;
+            .collect()
+    })?;
+    bar.finish_and_clear();
+    for (entry, destination) in entries.into_iter().zip(destinations) {
+        if let Some((source, path)) = destination {
+            insert(config, &mut plan, entry, source, path);
+        }
+    }
     Ok(plan)
 }
 
@@ -172,21 +456,26 @@ fn is_image(entry: &Path) -> bool {
         })
 }
 
-/// Given a directory entry, will insert it into the map of files to be
-/// relocated to their destinations, if the entry is a regular file, is not
-/// hidden, is an image, and a date can be extracted from the file either from
-/// its filename, exif data, or if its creation date is not today.
-fn maybe_insert(config: &TimeStampSortArgs, plan: &mut Plan, entry: DirEntry) {
-    let entry_path = entry.into_path();
-    if entry_path.is_file() && !crate::ocd::is_hidden(&entry_path) && is_image(&entry_path) {
-        if let Some((source, path)) = destination(config, &entry_path) {
-            let action = Action::Move {
-                date_source: Some(source),
-                path,
-            };
-            plan.insert(entry_path, action);
-        }
-    }
+/// Given a regular file, will insert it into the map of files to be
+/// relocated to their destinations, if the entry is an image, and a date can
+/// be extracted from the file either from its filename, exif data, or if its
+/// creation date is not today.
+fn insert(
+    config: &TimeStampSortArgs,
+    plan: &mut Plan,
+    entry_path: PathBuf,
+    source: DateSource,
+    path: PathBuf,
+) {
+    let date_source = Some(source);
+    let action = match config.action {
+        SortAction::Move => Action::Move { date_source, path },
+        SortAction::Copy => Action::Copy { date_source, path },
+        SortAction::Hardlink => Action::Hardlink { date_source, path },
+        SortAction::Symlink => Action::Symlink { date_source, path },
+        SortAction::Reflink => Action::Reflink { date_source, path },
+    };
+    plan.insert(entry_path, action);
 }
 
 /// This function tries to determine a destination for a given file.
@@ -195,12 +484,45 @@ fn maybe_insert(config: &TimeStampSortArgs, plan: &mut Plan, entry: DirEntry) {
 /// - If that fails, it tries to figure out a data from the filesystem metadata,
 ///   by looking at the created date field. If however the creation date is today,
 ///   it is discarded as we can assume that the original creation date has been lost.
+///
+/// When sorting into hourly buckets, files whose date has no time are sorted
+/// into the daily directory.
 fn destination(config: &TimeStampSortArgs, path: &PathBuf) -> Option<(DateSource, PathBuf)> {
     filename_date(path)
         .or_else(|| exif_date(path))
         .or_else(|| metadata_date(path))
-        .map(|(source, year, month, day)| {
-            let pathbuf = config.dir.join(format!("{year}-{month}-{day}"));
-            (source, pathbuf)
+        .map(|(source, year, month, day, time)| {
+            let dirname = match (config.bucket, time) {
+                (Bucket::Hour, Some(time)) => format!("{year}-{month}-{day} {:02}h", time.hour),
+                _ => format!("{year}-{month}-{day}"),
+            };
+            let root = config.dest.as_ref().unwrap_or(&config.dir);
+            (source, root.join(dirname))
         })
 }
+
+#[cfg(test)]
+mod test {
+    use crate::ocd::config::Config;
+    use crate::ocd::Cli;
+    use crate::ocd::OcdCommand;
+    use clap::CommandFactory;
+    use clap::FromArgMatches;
+
+    #[test]
+    fn command_line_overrides_config() {
+        let contents = "[tss]\ngit = true\nundo = true\nrecurse = true\nverify = true";
+        let config = Config::parse(contents).unwrap();
+        let matches =
+            Cli::command().get_matches_from(["ocd", "tss", "--no-verify", "--no-recurse"]);
+        if let OcdCommand::TimeStampSort(args) = Cli::from_arg_matches(&matches).unwrap().command {
+            let args = args.with_config(&config.tss, matches.subcommand_matches("tss").unwrap());
+            assert!(!args.verify);
+            assert!(!args.recurse);
+            assert!(args.git);
+            assert!(args.undo);
+        } else {
+            panic!()
+        }
+    }
+}
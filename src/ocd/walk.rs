@@ -0,0 +1,269 @@
+//! Entry discovery shared by all commands.
+//!
+//! The walker lists the entries under a directory, filtered by:
+//! - depth, where the entries directly inside the directory are at depth 1,
+//! - whether they are files, directories or both,
+//! - an optional glob pattern, matched against the path relative to the directory,
+//! - exclusion globs and `.ocdignore` files, both with gitignore semantics,
+//! - `.gitignore` files, only when operating through git,
+//! - whether they are hidden.
+//!
+//! Git's `.git` directories, or files in linked work trees, are never listed
+//! nor descended into.
+
+use crate::ocd::progress;
+use crate::ocd::Mode;
+use clap::Args;
+use glob::MatchOptions;
+use glob::Pattern;
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+use std::error::Error;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The name of the files listing entries to ignore, in gitignore format.
+pub(crate) const IGNORE_FILE_NAME: &str = ".ocdignore";
+
+/// Arguments controlling entry discovery, common to all commands.
+#[derive(Clone, Debug, Default, Args)]
+pub(crate) struct WalkArgs {
+    #[arg(help = r#"Skip entries matching the glob, with gitignore semantics.
+May be given multiple times."#)]
+    #[arg(long)]
+    #[arg(short = 'x')]
+    #[arg(value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    #[arg(help = r#"Descend at most this many levels below --dir.
+The entries directly inside --dir are at depth 1. Implies --recurse."#)]
+    #[arg(long = "max-depth")]
+    max_depth: Option<usize>,
+
+    #[arg(help = "Skip entries less than this many levels below --dir.")]
+    #[arg(long = "min-depth")]
+    min_depth: Option<usize>,
+
+    #[arg(help = "Include hidden files and directories, as mrn does by default.")]
+    #[arg(long)]
+    #[arg(overrides_with = "no_hidden")]
+    hidden: bool,
+
+    #[arg(help = "Skip hidden files and directories, as tss does by default.")]
+    #[arg(long)]
+    #[arg(overrides_with = "hidden")]
+    no_hidden: bool,
+}
+
+/// Lists the entries under a directory.
+pub(crate) struct Walker<'a> {
+    root: &'a Path,
+    args: &'a WalkArgs,
+    mode: Mode,
+    recurse: bool,
+    git: bool,
+    hidden: bool,
+    glob: Option<&'a str>,
+    progress: bool,
+}
+
+impl<'a> Walker<'a> {
+    pub(crate) fn new(root: &'a Path, args: &'a WalkArgs) -> Self {
+        Walker {
+            root,
+            args,
+            mode: Mode::All,
+            recurse: false,
+            git: false,
+            hidden: false,
+            glob: None,
+            progress: false,
+        }
+    }
+
+    pub(crate) fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub(crate) fn with_recurse(mut self, recurse: bool) -> Self {
+        self.recurse = recurse;
+        self
+    }
+
+    /// Operating through git also honours `.gitignore` files.
+    pub(crate) fn with_git(mut self, git: bool) -> Self {
+        self.git = git;
+        self
+    }
+
+    /// Includes hidden entries, unless `--no-hidden` is given. Otherwise
+    /// they are only included with `--hidden`.
+    pub(crate) fn with_hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    pub(crate) fn with_glob(mut self, glob: Option<&'a str>) -> Self {
+        self.glob = glob;
+        self
+    }
+
+    /// Shows a count of the entries found while walking.
+    pub(crate) fn with_progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Returns the matching entries, sorted by path.
+    pub(crate) fn entries(&self) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let glob = self.glob.map(Pattern::new).transpose()?;
+        let min_depth = self.args.min_depth.unwrap_or(1).max(1);
+        let max_depth = self.max_depth(glob.as_ref());
+
+        let mut overrides = OverrideBuilder::new(self.root);
+        for pattern in &self.args.exclude {
+            overrides.add(&format!("!{pattern}"))?;
+        }
+
+        let walk = WalkBuilder::new(self.root)
+            .max_depth(max_depth)
+            .hidden(!self.includes_hidden())
+            .ignore(false)
+            .git_ignore(self.git)
+            .git_global(self.git)
+            .git_exclude(self.git)
+            .add_custom_ignore_filename(IGNORE_FILE_NAME)
+            .filter_entry(|entry| entry.file_name() != ".git")
+            .overrides(overrides.build()?)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+
+        let spinner = progress::spinner(self.progress, "Discovering");
+        let mut entries = Vec::new();
+        for entry in walk {
+            let entry = entry?;
+            // The ignore files steer the walk, and are not entries themselves.
+            if entry.depth() < min_depth || entry.file_name() == IGNORE_FILE_NAME {
+                continue;
+            }
+            let mode_matches = entry.file_type().is_some_and(|file_type| match self.mode {
+                Mode::All => true,
+                Mode::Directories => file_type.is_dir(),
+                Mode::Files => file_type.is_file(),
+            });
+            if mode_matches && glob_matches(glob.as_ref(), self.root, entry.path()) {
+                entries.push(entry.into_path());
+                spinner.inc(1);
+            }
+        }
+        spinner.finish_and_clear();
+        Ok(entries)
+    }
+
+    fn includes_hidden(&self) -> bool {
+        self.args.hidden || (self.hidden && !self.args.no_hidden)
+    }
+
+    /// The depth to descend to. Without a maximum depth or recursion, a glob
+    /// pattern is followed as deep as it has path components.
+    fn max_depth(&self, glob: Option<&Pattern>) -> Option<usize> {
+        match (self.args.max_depth, self.recurse, glob) {
+            (Some(max_depth), _, _) => Some(max_depth),
+            (None, true, _) => None,
+            (None, false, Some(glob)) if glob.as_str().contains("**") => None,
+            (None, false, Some(glob)) => Some(Path::new(glob.as_str()).components().count()),
+            (None, false, None) => Some(1),
+        }
+    }
+}
+
+fn glob_matches(glob: Option<&Pattern>, root: &Path, path: &Path) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    // Paths that are not valid UTF-8 are matched with the invalid bytes
+    // replaced, so that e.g. `*.jpg` still matches them.
+    glob.is_none_or(|glob| {
+        path.strip_prefix(root)
+            .is_ok_and(|relative| glob.matches_with(&relative.to_string_lossy(), options))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn max_depth(recurse: bool, max_depth: Option<usize>, glob: Option<&str>) -> Option<usize> {
+        let args = WalkArgs {
+            max_depth,
+            ..WalkArgs::default()
+        };
+        let glob = glob.map(|glob| Pattern::new(glob).unwrap());
+        Walker::new(Path::new("."), &args)
+            .with_recurse(recurse)
+            .max_depth(glob.as_ref())
+    }
+
+    #[test]
+    fn max_depth_default() {
+        assert_eq!(Some(1), max_depth(false, None, None));
+        assert_eq!(None, max_depth(true, None, None));
+        assert_eq!(Some(3), max_depth(true, Some(3), None));
+    }
+
+    #[test]
+    fn max_depth_glob() {
+        assert_eq!(Some(1), max_depth(false, None, Some("*.mp3")));
+        assert_eq!(Some(2), max_depth(false, None, Some("*/*.mp3")));
+        assert_eq!(None, max_depth(false, None, Some("**/*.mp3")));
+    }
+
+    #[test]
+    fn skips_git_directory() {
+        let dir = std::env::temp_dir().join(format!("ocd-walk-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::write(dir.join(".git").join("HEAD"), "").unwrap();
+        std::fs::write(dir.join(".hidden"), "").unwrap();
+        let args = WalkArgs::default();
+        let entries = Walker::new(&dir, &args)
+            .with_recurse(true)
+            .with_hidden(true)
+            .entries()
+            .unwrap();
+        assert_eq!(vec![dir.join(".hidden")], entries);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn includes_hidden() {
+        let includes = |hidden, no_hidden, default| {
+            let args = WalkArgs {
+                hidden,
+                no_hidden,
+                ..WalkArgs::default()
+            };
+            Walker::new(Path::new("."), &args)
+                .with_hidden(default)
+                .includes_hidden()
+        };
+        assert!(includes(false, false, true));
+        assert!(!includes(false, true, true));
+        assert!(!includes(false, false, false));
+        assert!(includes(true, false, false));
+    }
+
+    #[test]
+    fn glob_matches_relative_path() {
+        let glob = Pattern::new("*.mp3").unwrap();
+        let root = Path::new("music");
+        assert!(glob_matches(Some(&glob), root, Path::new("music/a.mp3")));
+        assert!(!glob_matches(
+            Some(&glob),
+            root,
+            Path::new("music/album/a.mp3")
+        ));
+        assert!(glob_matches(None, root, Path::new("music/album/a.mp3")));
+    }
+}